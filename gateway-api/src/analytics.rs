@@ -1,9 +1,19 @@
 //! Analytics management.
 
-use crate::{util, Config};
+use crate::{
+	client_ip::ClientIpResolver,
+	pool::{Pool, PoolStats, Record},
+	scrub::Scrubber,
+	util, Config,
+};
 use axum::{
+	body::HttpBody,
 	extract::{Request},
-	http::header::{REFERER, USER_AGENT},
+	http::{
+		header::{CONTENT_LENGTH, HOST, REFERER, USER_AGENT},
+		uri::Authority,
+		HeaderMap, Method,
+	},
 	response::Response,
 };
 use chrono::{DateTime, Utc};
@@ -13,22 +23,17 @@ use std::{
 	net::IpAddr,
 	sync::Arc,
 	task::{Context, Poll},
-	time::Duration,
-};
-use tokio::{
-	select,
-	sync::mpsc::{unbounded_channel, UnboundedSender},
-	time::interval,
 };
 use tower::{Layer, Service};
-use tracing::{error, info};
-use crate::util::extract_peer_addr;
-
-const FLUSH_THRESHOLD: usize = 1024;
+use uuid::Uuid;
 
 /// An access log, emitted when accessing an endpoint.
 #[derive(Clone, Deserialize, Serialize)]
 pub struct Access {
+	/// Unique identifier of the access, allowing the gateway to ignore duplicates when a batch
+	/// is sent several times.
+	#[serde(default = "Uuid::new_v4")]
+	pub id: Uuid,
 	#[serde(with = "util::date_format")]
 	pub date: DateTime<Utc>,
 	pub peer_addr: Option<IpAddr>,
@@ -36,108 +41,260 @@ pub struct Access {
 	pub referer: Option<String>,
 	pub method: String,
 	pub uri: String,
+	/// The host the request was sent to, without port.
+	#[serde(default)]
+	pub host: Option<String>,
+	/// The status code of the response.
+	pub status: Option<u16>,
+	/// The time it took for the handler to produce the response, in milliseconds.
+	pub latency: Option<u64>,
+	/// The size of the response's body in bytes, if known.
+	pub response_size: Option<u64>,
+	/// The width of the client's screen in pixels, for accesses reported by browsers.
+	pub screen_width: Option<u32>,
+	/// The height of the client's screen in pixels, for accesses reported by browsers.
+	pub screen_height: Option<u32>,
+	/// Tells whether the client asked not to be tracked, with `DNT` or `Sec-GPC`.
+	#[serde(default)]
+	pub opt_out: bool,
+}
+
+impl Record for Access {
+	const ENDPOINT: &'static str = "access";
 }
 
 /// A pool containing accesses to be flushed.
-pub struct AccessPool {
-	sender: UnboundedSender<Access>,
-}
-
-impl AccessPool {
-	/// Creates a new pool
-	fn new() -> Self {
-		let (sender, mut receiver) = unbounded_channel::<Access>();
-		tokio::spawn(async move {
-			let mut interval = interval(Duration::from_secs(10));
-			let mut buf = Vec::with_capacity(FLUSH_THRESHOLD);
-			loop {
-				let max = FLUSH_THRESHOLD.saturating_sub(buf.len());
-				select! {
-					_ = interval.tick() => Self::flush(&mut buf).await,
-					len = receiver.recv_many(&mut buf, max) => {
-						// If the queue is closed, stop
-						if len == 0 {
-							break;
-						}
-						// If the buffer is full, flush
-						if buf.len() >= FLUSH_THRESHOLD {
-							Self::flush(&mut buf).await;
-						}
-					}
-				}
-			}
-			drop(receiver);
-			// Attempt to flush remaining accesses in buffer before stopping
-			while !buf.is_empty() {
-				Self::flush(&mut buf).await;
-				interval.tick().await;
-			}
-		});
-		Self {
-			sender,
+pub type AccessPool = Pool<Access>;
+
+/// Tells whether the client asked not to be tracked, with the `DNT: 1` (Do-Not-Track) or
+/// `Sec-GPC: 1` (Global Privacy Control) header.
+pub fn opts_out(headers: &HeaderMap) -> bool {
+	["dnt", "sec-gpc"]
+		.into_iter()
+		.any(|name| headers.get(name).is_some_and(|value| value == "1"))
+}
+
+/// Returns the host the given request was sent to, without port.
+fn request_host(request: &Request) -> Option<String> {
+	if let Some(host) = request.uri().host() {
+		return Some(host.to_owned());
+	}
+	let host = request.headers().get(HOST)?.to_str().ok()?;
+	let authority: Authority = host.parse().ok()?;
+	Some(authority.host().to_owned())
+}
+
+/// How to handle requests of clients asking not to be tracked (see [`opts_out`]).
+#[derive(Clone, Copy, Default)]
+pub enum PrivacyMode {
+	/// Track requests as usual. The access is still flagged in [`Access::opt_out`].
+	#[default]
+	Ignore,
+	/// Track requests without the peer address and user agent.
+	Anonymize,
+	/// Do not track requests.
+	Skip,
+}
+
+/// Predicate over a request, telling whether it must be tracked.
+type Filter = Arc<dyn Fn(&Request) -> bool + Send + Sync>;
+
+/// Tells whether `path` matches `pattern`.
+///
+/// A pattern ending with `*` matches any path starting with the rest of the pattern. Any other
+/// pattern matches the path exactly.
+fn path_matches(pattern: &str, path: &str) -> bool {
+	match pattern.strip_suffix('*') {
+		Some(prefix) => path.starts_with(prefix),
+		None => path == pattern,
+	}
+}
+
+/// Rules deciding which requests are tracked, and what is recorded.
+#[derive(Clone, Default)]
+struct Rules {
+	/// If not empty, only paths matching one of these patterns are tracked.
+	include: Vec<String>,
+	/// Paths matching one of these patterns are not tracked.
+	exclude: Vec<String>,
+	/// If not empty, only requests with one of these methods are tracked.
+	methods: Vec<Method>,
+	/// Sampling rates, along with the pattern of paths they apply to.
+	sampling: Vec<(String, f64)>,
+	/// Predicates that must all hold for a request to be tracked.
+	filters: Vec<Filter>,
+	/// How to handle requests of clients asking not to be tracked.
+	privacy: PrivacyMode,
+	/// Scrubber applied to URIs and referers.
+	scrubber: Scrubber,
+	/// Resolver of the address of clients.
+	client_ip: ClientIpResolver,
+}
+
+impl Rules {
+	/// Tells whether the given request must be tracked.
+	fn matches(&self, request: &Request) -> bool {
+		let path = request.uri().path();
+		let included = self.include.is_empty()
+			|| self
+				.include
+				.iter()
+				.any(|pattern| path_matches(pattern, path));
+		let excluded = self
+			.exclude
+			.iter()
+			.any(|pattern| path_matches(pattern, path));
+		let method = self.methods.is_empty() || self.methods.contains(request.method());
+		if !included || excluded || !method {
+			return false;
+		}
+		if !self.filters.iter().all(|filter| filter(request)) {
+			return false;
+		}
+		if matches!(self.privacy, PrivacyMode::Skip) && opts_out(request.headers()) {
+			return false;
 		}
+		// The first matching sampling rate applies
+		self.sampling
+			.iter()
+			.find(|(pattern, _)| path_matches(pattern, path))
+			.is_none_or(|(_, rate)| rand::random_bool(*rate))
 	}
+}
+
+/// Builder for [`AnalyticsLayer`].
+///
+/// Path patterns either match a path exactly, or match every path starting with a prefix when
+/// ending with `*` (for example `/static/*`).
+#[derive(Default)]
+pub struct AnalyticsLayerBuilder {
+	rules: Rules,
+	client_ip: Option<ClientIpResolver>,
+}
 
-	/// Pushes new access to the pool.
-	async fn push(&self, access: Access) {
-		// If the other side is closed, that means the server is stopping anyway
-		let _ = self.sender.send(access);
+impl AnalyticsLayerBuilder {
+	/// Only tracks requests whose path matches `pattern`, or any other included pattern.
+	pub fn include(mut self, pattern: impl Into<String>) -> Self {
+		self.rules.include.push(pattern.into());
+		self
 	}
 
-	/// Flushes the pool's content, clearing it.
+	/// Does not track requests whose path matches `pattern`.
 	///
-	/// If the pool could not be flushed, its content is kept for a future retry.
-	async fn flush(pool: &mut Vec<Access>) {
-		if pool.is_empty() {
-			return;
-		}
-		info!(count = pool.len(), "attempt to flush accesses");
-		let config = Config::get();
-		let url = format!("{}/access", config.gateway_url);
-		// HTTP request to push accesses
-		let client = reqwest::Client::new();
-		let res = client
-			.put(&url)
-			.basic_auth(&config.gateway_property, Some(&config.gateway_secret))
-			.json(pool)
-			.send()
-			.await;
-		let response = match res {
-			Ok(response) => response,
-			Err(error) => {
-				error!(url, %error, "access: HTTP call failure");
-				return;
-			}
-		};
-		let status = response.status();
-		if !status.is_success() {
-			error!(url, %status, "access: HTTP call failure");
-			return;
+	/// Exclusions take precedence over inclusions.
+	pub fn exclude(mut self, pattern: impl Into<String>) -> Self {
+		self.rules.exclude.push(pattern.into());
+		self
+	}
+
+	/// Only tracks requests with the given method, or any other allowed method.
+	pub fn method(mut self, method: Method) -> Self {
+		self.rules.methods.push(method);
+		self
+	}
+
+	/// Tracks only a fraction `rate` (between `0` and `1`) of the requests whose path matches
+	/// `pattern`.
+	///
+	/// If several sampling rates match a path, the first one registered applies.
+	///
+	/// # Panics
+	///
+	/// The function panics if `rate` is not between `0` and `1` (including if it is `NaN`).
+	pub fn sample(mut self, pattern: impl Into<String>, rate: f64) -> Self {
+		assert!(
+			(0. ..=1.).contains(&rate),
+			"invalid sampling rate {rate}, expected a value between 0 and 1"
+		);
+		self.rules.sampling.push((pattern.into(), rate));
+		self
+	}
+
+	/// Only tracks requests for which `filter` returns `true`.
+	pub fn filter<F>(mut self, filter: F) -> Self
+	where
+		F: Fn(&Request) -> bool + Send + Sync + 'static,
+	{
+		self.rules.filters.push(Arc::new(filter));
+		self
+	}
+
+	/// Sets how to handle requests of clients asking not to be tracked.
+	///
+	/// By default, [`PrivacyMode::Ignore`] applies.
+	pub fn privacy(mut self, mode: PrivacyMode) -> Self {
+		self.rules.privacy = mode;
+		self
+	}
+
+	/// Sets the scrubber removing personal data from URIs and referers.
+	///
+	/// By default, [`Scrubber::default`] applies.
+	pub fn scrubber(mut self, scrubber: Scrubber) -> Self {
+		self.rules.scrubber = scrubber;
+		self
+	}
+
+	/// Sets the resolver of the address of clients.
+	///
+	/// By default, the resolver configured by [`Config`] applies.
+	pub fn client_ip(mut self, resolver: ClientIpResolver) -> Self {
+		self.client_ip = Some(resolver);
+		self
+	}
+
+	/// Creates the layer.
+	pub fn build(mut self) -> AnalyticsLayer {
+		self.rules.client_ip = self
+			.client_ip
+			.unwrap_or_else(|| ClientIpResolver::from_config(Config::get()));
+		AnalyticsLayer {
+			pool: Arc::new(AccessPool::new()),
+			rules: Arc::new(self.rules),
 		}
-		// Success: clear pool
-		info!("clear accesses pool");
-		pool.clear();
 	}
 }
 
 /// Analytics collection layer.
 ///
+/// By default, every request is tracked. Use [`AnalyticsLayer::builder`] to select the requests
+/// to track.
+///
 /// **Note**: This layer requires connection information. The following call is required on the
 /// router:
 ///
-/// ```
+/// ```ignore
 /// into_make_service_with_connect_info::<SocketAddr>()
 /// ```
 #[derive(Clone)]
 pub struct AnalyticsLayer {
 	pool: Arc<AccessPool>,
+	rules: Arc<Rules>,
+}
+
+impl AnalyticsLayer {
+	/// Returns a builder to configure the layer.
+	pub fn builder() -> AnalyticsLayerBuilder {
+		AnalyticsLayerBuilder::default()
+	}
+
+	/// Returns the counters of the underlying pool.
+	pub fn stats(&self) -> &PoolStats {
+		self.pool.stats()
+	}
+
+	/// Waits for the accesses tracked so far to be flushed, or spooled if the gateway cannot be
+	/// reached. Accesses tracked afterwards are never sent.
+	///
+	/// This is meant to be called once the server has stopped, before exiting.
+	pub async fn shutdown(&self) {
+		self.pool.shutdown().await;
+	}
 }
 
 impl Default for AnalyticsLayer {
 	fn default() -> Self {
-		Self {
-			pool: Arc::new(AccessPool::new()),
-		}
+		Self::builder().build()
 	}
 }
 
@@ -148,6 +305,7 @@ impl<S> Layer<S> for AnalyticsLayer {
 		AnalyticsMiddleware {
 			inner,
 			pool: self.pool.clone(),
+			rules: self.rules.clone(),
 		}
 	}
 }
@@ -157,6 +315,7 @@ impl<S> Layer<S> for AnalyticsLayer {
 pub struct AnalyticsMiddleware<S> {
 	inner: S,
 	pool: Arc<AccessPool>,
+	rules: Arc<Rules>,
 }
 
 impl<S> Service<Request> for AnalyticsMiddleware<S>
@@ -173,8 +332,14 @@ where
 	}
 
 	fn call(&mut self, request: Request) -> Self::Future {
-		let (request, peer_addr) = extract_peer_addr(request);
-		let access = Access {
+		if !self.rules.matches(&request) {
+			return Box::pin(self.inner.call(request));
+		}
+		let peer_addr = self.rules.client_ip.resolve_request(&request);
+		let opt_out = opts_out(request.headers());
+		let scrubber = &self.rules.scrubber;
+		let mut access = Access {
+			id: Uuid::new_v4(),
 			date: Utc::now(),
 			peer_addr,
 			user_agent: request
@@ -186,16 +351,120 @@ where
 				.headers()
 				.get(REFERER)
 				.and_then(|ua| ua.to_str().ok())
-				.map(str::to_owned),
+				.map(|referer| scrubber.scrub(referer).into_owned()),
 			method: request.method().to_string(),
-			uri: request.uri().to_string(),
+			uri: scrubber.scrub(&request.uri().to_string()).into_owned(),
+			host: request_host(&request),
+			status: None,
+			latency: None,
+			response_size: None,
+			screen_width: None,
+			screen_height: None,
+			opt_out,
 		};
+		if opt_out && matches!(self.rules.privacy, PrivacyMode::Anonymize) {
+			access.peer_addr = None;
+			access.user_agent = None;
+		}
 		let pool = self.pool.clone();
 		let future = self.inner.call(request);
 		Box::pin(async move {
-			pool.push(access).await;
 			let response: Response = future.await?;
+			access.status = Some(response.status().as_u16());
+			// Cannot be negative unless going back in time
+			access.latency = Some((Utc::now() - access.date).num_milliseconds() as u64);
+			access.response_size = response
+				.headers()
+				.get(CONTENT_LENGTH)
+				.and_then(|len| len.to_str().ok()?.parse().ok())
+				.or_else(|| response.body().size_hint().exact());
+			pool.push(access).await;
 			Ok(response)
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use axum::body::Body;
+
+	fn request(method: Method, uri: &str) -> Request {
+		Request::builder()
+			.method(method)
+			.uri(uri)
+			.body(Body::empty())
+			.unwrap()
+	}
+
+	fn get(uri: &str) -> Request {
+		request(Method::GET, uri)
+	}
+
+	#[test]
+	fn patterns() {
+		assert!(path_matches("/health", "/health"));
+		assert!(!path_matches("/health", "/health/db"));
+		assert!(path_matches("/static/*", "/static/app.js"));
+		assert!(path_matches("/static/*", "/static/"));
+		assert!(!path_matches("/static/*", "/static"));
+		assert!(path_matches("*", "/anything"));
+	}
+
+	#[test]
+	fn include_exclude() {
+		let rules = AnalyticsLayer::builder()
+			.include("/api/*")
+			.include("/")
+			.exclude("/api/internal/*")
+			.rules;
+		assert!(rules.matches(&get("/")));
+		assert!(rules.matches(&get("/api/users?page=2")));
+		assert!(!rules.matches(&get("/api/internal/metrics")));
+		assert!(!rules.matches(&get("/robots.txt")));
+	}
+
+	#[test]
+	fn methods() {
+		let rules = AnalyticsLayer::builder().method(Method::GET).rules;
+		assert!(rules.matches(&get("/")));
+		assert!(!rules.matches(&request(Method::POST, "/")));
+	}
+
+	#[test]
+	fn filters() {
+		let rules = AnalyticsLayer::builder()
+			.filter(|request| request.uri().query().is_none())
+			.rules;
+		assert!(rules.matches(&get("/")));
+		assert!(!rules.matches(&get("/?debug")));
+	}
+
+	#[test]
+	fn sampling() {
+		let rules = AnalyticsLayer::builder()
+			.sample("/static/*", 0.)
+			.sample("*", 1.)
+			.rules;
+		assert!(!rules.matches(&get("/static/app.js")));
+		assert!(rules.matches(&get("/")));
+	}
+
+	#[test]
+	#[should_panic]
+	fn invalid_sampling_rate() {
+		let _ = AnalyticsLayer::builder().sample("*", f64::NAN);
+	}
+
+	#[test]
+	fn privacy() {
+		let mut opted_out = get("/");
+		opted_out.headers_mut().insert("dnt", "1".parse().unwrap());
+		assert!(opts_out(opted_out.headers()));
+		let rules = AnalyticsLayer::builder().rules;
+		assert!(rules.matches(&opted_out));
+		let rules = AnalyticsLayer::builder().privacy(PrivacyMode::Skip).rules;
+		assert!(!rules.matches(&opted_out));
+		assert!(rules.matches(&get("/")));
+	}
+}
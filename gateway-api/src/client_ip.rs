@@ -0,0 +1,318 @@
+//! Resolution of the IP address of clients, behind reverse proxies.
+
+use crate::Config;
+use axum::{
+	extract::{ConnectInfo, Request},
+	http::HeaderMap,
+};
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+
+/// A header in which proxies pass the address of the client.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpHeader {
+	/// `Forwarded` (RFC 7239), using the `for` parameter of each element.
+	Forwarded,
+	/// `X-Forwarded-For`, a comma-separated list of addresses.
+	XForwardedFor,
+	/// `X-Real-IP`, a single address.
+	XRealIp,
+	/// `CF-Connecting-IP`, a single address set by Cloudflare.
+	CfConnectingIp,
+}
+
+/// The default precedence order of headers.
+const DEFAULT_HEADERS: &[IpHeader] = &[
+	IpHeader::Forwarded,
+	IpHeader::XForwardedFor,
+	IpHeader::XRealIp,
+	IpHeader::CfConnectingIp,
+];
+
+/// Parses a node of a `Forwarded` header, such as `192.0.2.60`, `"192.0.2.60:8080"` or
+/// `"[2001:db8::17]:4711"`.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+	let node = node.trim().trim_matches('"');
+	if let Some(rest) = node.strip_prefix('[') {
+		let (addr, _) = rest.split_once(']')?;
+		return addr.parse().ok();
+	}
+	node.parse()
+		.ok()
+		.or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+impl IpHeader {
+	/// Returns the header's name.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Forwarded => "forwarded",
+			Self::XForwardedFor => "x-forwarded-for",
+			Self::XRealIp => "x-real-ip",
+			Self::CfConnectingIp => "cf-connecting-ip",
+		}
+	}
+
+	/// Returns the addresses in the header, from the farthest from the server to the nearest.
+	///
+	/// Addresses that cannot be parsed are `None`. If the header is absent, the function returns
+	/// `None`.
+	fn addresses(&self, headers: &HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+		let mut values = headers.get_all(self.name()).iter().peekable();
+		values.peek()?;
+		let values = values.map(|value| value.to_str().ok());
+		let addresses = match self {
+			Self::Forwarded => values
+				.flat_map(|value| value.unwrap_or_default().split(','))
+				.map(|element| {
+					element.split(';').find_map(|pair| {
+						let (name, value) = pair.split_once('=')?;
+						if !name.trim().eq_ignore_ascii_case("for") {
+							return None;
+						}
+						parse_forwarded_node(value)
+					})
+				})
+				.collect(),
+			Self::XForwardedFor => values
+				.flat_map(|value| value.unwrap_or_default().split(','))
+				.map(|addr| addr.trim().parse().ok())
+				.collect(),
+			Self::XRealIp | Self::CfConnectingIp => {
+				values.map(|value| value?.trim().parse().ok()).collect()
+			}
+		};
+		Some(addresses)
+	}
+}
+
+/// Resolves the IP address of the client of a request.
+///
+/// Headers passing the client's address are honored only if the request comes from a trusted
+/// proxy, since anyone can set them otherwise. The first header present, in order of precedence,
+/// is used. Its addresses are walked from the nearest to the farthest, skipping trusted proxies.
+///
+/// By default, no proxy is trusted, so the address of the socket's peer is used.
+#[derive(Clone, Debug)]
+pub struct ClientIpResolver {
+	/// The networks of trusted proxies.
+	trusted_proxies: Vec<IpNet>,
+	/// The headers to read, in order of precedence.
+	headers: Vec<IpHeader>,
+}
+
+impl Default for ClientIpResolver {
+	fn default() -> Self {
+		Self {
+			trusted_proxies: vec![],
+			headers: DEFAULT_HEADERS.to_vec(),
+		}
+	}
+}
+
+impl ClientIpResolver {
+	/// Returns the resolver configured by [`Config`].
+	pub fn from_config(config: &Config) -> Self {
+		let mut resolver = Self {
+			trusted_proxies: config.gateway_trusted_proxies.clone(),
+			..Self::default()
+		};
+		if !config.gateway_client_ip_headers.is_empty() {
+			resolver.headers = config.gateway_client_ip_headers.clone();
+		}
+		resolver
+	}
+
+	/// Trusts proxies in the given network.
+	pub fn trust(mut self, network: IpNet) -> Self {
+		self.trusted_proxies.push(network);
+		self
+	}
+
+	/// Sets the headers to read, in order of precedence.
+	///
+	/// By default, `Forwarded`, `X-Forwarded-For`, `X-Real-IP` and `CF-Connecting-IP` are read,
+	/// in this order.
+	pub fn headers(mut self, headers: Vec<IpHeader>) -> Self {
+		self.headers = headers;
+		self
+	}
+
+	/// Tells whether `addr` is a trusted proxy.
+	fn is_trusted(&self, addr: IpAddr) -> bool {
+		// IPv4 clients may appear as IPv4-mapped IPv6 addresses on dual-stack sockets
+		let addr = addr.to_canonical();
+		self.trusted_proxies
+			.iter()
+			.any(|network| network.contains(&addr))
+	}
+
+	/// Returns the address of the client, given the address of the socket's peer and the
+	/// request's headers.
+	///
+	/// Resolution performs no I/O, so this is synchronous: it may be called from
+	/// middleware's `call` as well as outside of an async context.
+	pub fn resolve(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+		let mut client = peer?;
+		if !self.is_trusted(client) {
+			return Some(client);
+		}
+		let Some(addresses) = self
+			.headers
+			.iter()
+			.find_map(|header| header.addresses(headers))
+		else {
+			return Some(client);
+		};
+		for addr in addresses.into_iter().rev() {
+			// Addresses beyond an invalid one cannot be trusted
+			let Some(addr) = addr else {
+				break;
+			};
+			client = addr;
+			if !self.is_trusted(client) {
+				break;
+			}
+		}
+		Some(client)
+	}
+
+	/// Returns the address of the client of `request`.
+	///
+	/// The address of the socket's peer is taken from the request's connection information. If
+	/// not available, the function returns `None`.
+	pub fn resolve_request(&self, request: &Request) -> Option<IpAddr> {
+		let peer = request
+			.extensions()
+			.get::<ConnectInfo<SocketAddr>>()
+			.map(|ConnectInfo(addr)| addr.ip());
+		self.resolve(peer, request.headers())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use axum::http::HeaderValue;
+
+	fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		for (name, value) in pairs {
+			headers.append(*name, HeaderValue::from_static(value));
+		}
+		headers
+	}
+
+	fn addr(s: &str) -> IpAddr {
+		s.parse().unwrap()
+	}
+
+	fn resolver() -> ClientIpResolver {
+		ClientIpResolver::default().trust("10.0.0.0/8".parse().unwrap())
+	}
+
+	#[test]
+	fn no_peer() {
+		let headers = headers(&[("x-forwarded-for", "203.0.113.7")]);
+		assert_eq!(resolver().resolve(None, &headers), None);
+	}
+
+	#[test]
+	fn untrusted_peer() {
+		let headers = headers(&[("x-forwarded-for", "203.0.113.7")]);
+		let client = resolver().resolve(Some(addr("198.51.100.1")), &headers);
+		assert_eq!(client, Some(addr("198.51.100.1")));
+	}
+
+	#[test]
+	fn no_trusted_proxy_by_default() {
+		let headers = headers(&[("x-forwarded-for", "203.0.113.7")]);
+		let client = ClientIpResolver::default().resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("10.0.0.1")));
+	}
+
+	#[test]
+	fn trusted_peer_without_header() {
+		let client = resolver().resolve(Some(addr("10.0.0.1")), &HeaderMap::new());
+		assert_eq!(client, Some(addr("10.0.0.1")));
+	}
+
+	#[test]
+	fn trusted_proxies() {
+		let headers = headers(&[("x-forwarded-for", "203.0.113.7, 10.0.0.2")]);
+		let client = resolver().resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("203.0.113.7")));
+	}
+
+	#[test]
+	fn spoofed_address() {
+		// The client prepended an address of its own, which must not be trusted
+		let headers = headers(&[("x-forwarded-for", "192.0.2.1, 203.0.113.7")]);
+		let client = resolver().resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("203.0.113.7")));
+	}
+
+	#[test]
+	fn invalid_address() {
+		let headers = headers(&[("x-forwarded-for", "192.0.2.1, garbage, 10.0.0.2")]);
+		let client = resolver().resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("10.0.0.2")));
+	}
+
+	#[test]
+	fn multiple_header_values() {
+		let headers = headers(&[
+			("x-forwarded-for", "203.0.113.7"),
+			("x-forwarded-for", "10.0.0.2"),
+		]);
+		let client = resolver().resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("203.0.113.7")));
+	}
+
+	#[test]
+	fn ipv4_mapped_peer() {
+		let headers = headers(&[("x-forwarded-for", "203.0.113.7")]);
+		let client = resolver().resolve(Some(addr("::ffff:10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("203.0.113.7")));
+	}
+
+	#[test]
+	fn ipv4_mapped_proxy() {
+		let headers = headers(&[("x-forwarded-for", "203.0.113.7, ::ffff:10.0.0.2")]);
+		let client = resolver().resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("203.0.113.7")));
+	}
+
+	#[test]
+	fn ipv4_mapped_untrusted_peer() {
+		let headers = headers(&[("x-forwarded-for", "203.0.113.7")]);
+		let client = resolver().resolve(Some(addr("::ffff:198.51.100.1")), &headers);
+		assert_eq!(client, Some(addr("::ffff:198.51.100.1")));
+	}
+
+	#[test]
+	fn forwarded() {
+		let headers = headers(&[(
+			"forwarded",
+			r#"for=192.0.2.60;proto=http, for="[2001:db8::17]:4711", for="10.0.0.2:8080""#,
+		)]);
+		let client = resolver().resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("2001:db8::17")));
+	}
+
+	#[test]
+	fn header_precedence() {
+		let headers = headers(&[
+			("x-forwarded-for", "203.0.113.7"),
+			("x-real-ip", "198.51.100.9"),
+		]);
+		let client = resolver().resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("203.0.113.7")));
+		let client = resolver()
+			.headers(vec![IpHeader::XRealIp])
+			.resolve(Some(addr("10.0.0.1")), &headers);
+		assert_eq!(client, Some(addr("198.51.100.9")));
+	}
+}
@@ -0,0 +1,111 @@
+//! Custom events tracking.
+
+use crate::{
+	pool::{Pool, PoolStats, Record},
+	util,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A custom event, describing a business action such as a signup or a purchase.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Event {
+	/// Unique identifier of the event, allowing the gateway to ignore duplicates when a batch is
+	/// sent several times.
+	#[serde(default = "Uuid::new_v4")]
+	pub id: Uuid,
+	#[serde(with = "util::date_format")]
+	pub date: DateTime<Utc>,
+	/// The name of the event.
+	pub name: String,
+	/// Arbitrary properties attached to the event.
+	#[serde(default)]
+	pub properties: serde_json::Value,
+}
+
+impl Event {
+	/// Creates a new event happening now.
+	pub fn new(name: impl Into<String>, properties: serde_json::Value) -> Self {
+		Self {
+			id: Uuid::new_v4(),
+			date: Utc::now(),
+			name: name.into(),
+			properties,
+		}
+	}
+}
+
+impl Record for Event {
+	const ENDPOINT: &'static str = "event";
+}
+
+/// Tracker of custom events, sending them to the gateway in batches.
+///
+/// Clones share the same pool. Events still buffered when the last clone is dropped are flushed
+/// in the background; call [`EventTracker::shutdown`] before exiting to wait for them.
+///
+/// **Note**: The tracker must be created from within a tokio runtime, which runs the task
+/// flushing its events.
+#[derive(Clone)]
+pub struct EventTracker {
+	pool: Arc<Pool<Event>>,
+}
+
+impl EventTracker {
+	/// Creates a tracker.
+	pub fn new() -> Self {
+		Self {
+			pool: Arc::new(Pool::new()),
+		}
+	}
+
+	/// Records an event with the given `name` and `properties`.
+	pub async fn track(&self, name: impl Into<String>, properties: serde_json::Value) {
+		self.pool.push(Event::new(name, properties)).await;
+	}
+
+	/// Returns the counters of the underlying pool.
+	pub fn stats(&self) -> &PoolStats {
+		self.pool.stats()
+	}
+
+	/// Waits for the events tracked so far to be flushed, or spooled if the gateway cannot be
+	/// reached. Events tracked afterwards are never sent.
+	pub async fn shutdown(&self) {
+		self.pool.shutdown().await;
+	}
+}
+
+impl Default for EventTracker {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn id_defaults_to_new() {
+		let payload = r#"{"date": "2025-01-01 10:00:00", "name": "signup"}"#;
+		let a: Event = serde_json::from_str(payload).unwrap();
+		let b: Event = serde_json::from_str(payload).unwrap();
+		assert_ne!(a.id, b.id);
+		assert_eq!(a.properties, serde_json::Value::Null);
+	}
+
+	#[test]
+	fn roundtrip() {
+		let event = Event::new("purchase", json!({"amount": 42}));
+		let event_: Event = serde_json::from_str(&serde_json::to_string(&event).unwrap()).unwrap();
+		assert_eq!(event_.id, event.id);
+		assert_eq!(event_.name, "purchase");
+		assert_eq!(event_.properties, json!({"amount": 42}));
+		// Dates are sent with a precision of a second
+		assert_eq!(event_.date.timestamp(), event.date.timestamp());
+	}
+}
@@ -1,11 +1,26 @@
 //! API for the gateway and various utilities for HTTP services.
 
 pub mod analytics;
+pub mod client_ip;
+pub mod event;
 pub mod log;
+pub mod pool;
+pub mod scrub;
+pub mod sign;
+mod spool;
 pub mod util;
 
-use serde::Deserialize;
-use std::sync::OnceLock;
+use crate::{client_ip::IpHeader, pool::DropPolicy};
+use ipnet::IpNet;
+use serde::{de, Deserialize, Deserializer};
+use std::{path::PathBuf, sync::OnceLock};
+
+/// The default maximum size of the spool, in bytes.
+const DEFAULT_SPOOL_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// The default maximum number of records waiting to be flushed, per pool.
+const DEFAULT_POOL_CAPACITY: usize = 16384;
+/// The default fraction of records kept with [`DropPolicy::Sample`].
+const DEFAULT_POOL_SAMPLE_RATE: f64 = 0.1;
 
 /// Configuration for the API.
 #[derive(Deserialize)]
@@ -16,11 +31,63 @@ pub struct Config {
 	pub gateway_property: String,
 	/// The property's secret.
 	pub gateway_secret: String,
+	/// The directory in which data that could not be sent to the gateway is spooled.
+	///
+	/// If unset, such data is kept in memory only.
+	pub gateway_spool_dir: Option<PathBuf>,
+	/// The maximum size of the spool on disk, in bytes.
+	#[serde(default = "default_spool_max_size")]
+	pub gateway_spool_max_size: u64,
+	/// The maximum number of records waiting to be flushed, per pool.
+	#[serde(default = "default_pool_capacity")]
+	pub gateway_pool_capacity: usize,
+	/// The policy to apply when the pool is under pressure.
+	#[serde(default)]
+	pub gateway_pool_policy: DropPolicy,
+	/// With [`DropPolicy::Sample`], the fraction of records to keep, between `0` and `1`.
+	#[serde(
+		default = "default_pool_sample_rate",
+		deserialize_with = "deserialize_rate"
+	)]
+	pub gateway_pool_sample_rate: f64,
+	/// The property's signing key, as hexadecimal. If set, requests to the gateway are signed.
+	pub gateway_signing_key: Option<String>,
+	/// The networks of trusted reverse proxies, whose headers are used to resolve the address
+	/// of clients.
+	#[serde(default)]
+	pub gateway_trusted_proxies: Vec<IpNet>,
+	/// The headers passing the address of clients, in order of precedence. If empty, the
+	/// default order applies.
+	#[serde(default)]
+	pub gateway_client_ip_headers: Vec<IpHeader>,
 
 	/// The current service's hostname.
 	pub host: String,
 }
 
+fn default_spool_max_size() -> u64 {
+	DEFAULT_SPOOL_MAX_SIZE
+}
+
+fn default_pool_capacity() -> usize {
+	DEFAULT_POOL_CAPACITY
+}
+
+fn default_pool_sample_rate() -> f64 {
+	DEFAULT_POOL_SAMPLE_RATE
+}
+
+/// Deserializes a fraction, rejecting values outside of `[0, 1]` (including `NaN`).
+fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+	let rate = f64::deserialize(deserializer)?;
+	if !(0. ..=1.).contains(&rate) {
+		return Err(de::Error::custom(format!(
+			"invalid rate {rate}, expected a value between 0 and 1"
+		)));
+	}
+	Ok(rate)
+}
+
 impl Config {
 	/// Returns the configuration from the current environment.
 	///
@@ -39,3 +106,27 @@ pub async fn robots() -> &'static str {
 		format!(include_str!("robots.txt"), config.host)
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde::de::value::{Error, F64Deserializer};
+
+	fn rate(rate: f64) -> Result<f64, Error> {
+		deserialize_rate(F64Deserializer::<Error>::new(rate))
+	}
+
+	#[test]
+	fn valid_rate() {
+		assert_eq!(rate(0.).unwrap(), 0.);
+		assert_eq!(rate(0.25).unwrap(), 0.25);
+		assert_eq!(rate(1.).unwrap(), 1.);
+	}
+
+	#[test]
+	fn invalid_rate() {
+		for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.1, 1.5] {
+			assert!(rate(value).is_err(), "{value}");
+		}
+	}
+}
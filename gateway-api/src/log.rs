@@ -1,5 +1,6 @@
 //! Logging layer.
 
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use axum::extract::Request;
 use axum::response::Response;
@@ -7,11 +8,26 @@ use chrono::{Utc};
 use futures_util::future::BoxFuture;
 use tower::{Layer, Service};
 use tracing::info;
-use crate::util::{date_format, extract_peer_addr};
+use crate::client_ip::ClientIpResolver;
+use crate::util::date_format;
 
 /// Layer printing logs.
-#[derive(Clone)]
-pub struct LogLayer;
+///
+/// By default, the address of the socket's peer is logged. Use [`LogLayer::new`] to resolve
+/// the address of clients behind reverse proxies.
+#[derive(Clone, Default)]
+pub struct LogLayer {
+    client_ip: Arc<ClientIpResolver>,
+}
+
+impl LogLayer {
+    /// Creates a layer resolving the address of clients with `client_ip`.
+    pub fn new(client_ip: Arc<ClientIpResolver>) -> Self {
+        Self {
+            client_ip,
+        }
+    }
+}
 
 impl<S> Layer<S> for LogLayer {
     type Service = LogMiddleware<S>;
@@ -19,6 +35,7 @@ impl<S> Layer<S> for LogLayer {
     fn layer(&self, inner: S) -> Self::Service {
         LogMiddleware {
             inner,
+            client_ip: self.client_ip.clone(),
         }
     }
 }
@@ -26,6 +43,7 @@ impl<S> Layer<S> for LogLayer {
 #[derive(Clone)]
 pub struct LogMiddleware<S> {
     inner: S,
+    client_ip: Arc<ClientIpResolver>,
 }
 
 impl<S> Service<Request> for LogMiddleware<S>
@@ -44,7 +62,7 @@ where
     fn call(&mut self, request: Request) -> Self::Future {
         let begin = Utc::now();
         let ts = begin.format(date_format::FORMAT);
-        let (request, peer_addr) = extract_peer_addr(request);
+        let peer_addr = self.client_ip.resolve_request(&request);
         let path = request.uri().clone();
         let future = self.inner.call(request);
         Box::pin(async move {
@@ -0,0 +1,493 @@
+//! Pools batching records before sending them to the gateway.
+
+use crate::{
+	sign,
+	spool::{Spool, Spooled},
+	Config,
+};
+use chrono::Utc;
+use reqwest::header::CONTENT_TYPE;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+	collections::VecDeque,
+	sync::{
+		atomic::{
+			AtomicBool, AtomicU64,
+			Ordering::{Acquire, Relaxed, Release},
+		},
+		Arc, Mutex,
+	},
+	time::Duration,
+};
+use tokio::{select, sync::Notify, task::JoinHandle, time::interval};
+use tracing::{error, info, warn};
+
+const FLUSH_THRESHOLD: usize = 1024;
+
+/// A kind of record that can be sent to the gateway.
+pub trait Record: Serialize + DeserializeOwned + Send + Sync + 'static {
+	/// The name of the gateway's endpoint receiving records of this kind.
+	///
+	/// It is also used to name the spool's subdirectory and in logs.
+	const ENDPOINT: &'static str;
+}
+
+/// Policy applied to new records when the pool is under pressure.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropPolicy {
+	/// When the pool is full, new records are discarded.
+	#[default]
+	DropNewest,
+	/// When the pool is full, the oldest records are discarded to make room for new ones.
+	DropOldest,
+	/// Once the pool is half full, only a fraction of new records is kept. When the pool is
+	/// full, new records are discarded.
+	Sample,
+}
+
+/// Counters of records going through a pool.
+#[derive(Default)]
+pub struct PoolStats {
+	enqueued: AtomicU64,
+	flushed: AtomicU64,
+	dropped: AtomicU64,
+	retried: AtomicU64,
+}
+
+impl PoolStats {
+	/// Returns the number of records accepted in the pool.
+	pub fn enqueued(&self) -> u64 {
+		self.enqueued.load(Relaxed)
+	}
+
+	/// Returns the number of records successfully sent to the gateway.
+	pub fn flushed(&self) -> u64 {
+		self.flushed.load(Relaxed)
+	}
+
+	/// Returns the number of records that have been discarded, either by the drop policy or
+	/// because the spool was full.
+	pub fn dropped(&self) -> u64 {
+		self.dropped.load(Relaxed)
+	}
+
+	/// Returns the number of records that could not be sent and have been kept to be retried.
+	pub fn retried(&self) -> u64 {
+		self.retried.load(Relaxed)
+	}
+}
+
+/// State shared between a pool and its flushing task.
+struct PoolShared<T> {
+	/// The queue of records waiting to be flushed.
+	queue: Mutex<VecDeque<T>>,
+	/// Notified when the queue reaches the flush threshold, or when the pool is closed.
+	notify: Notify,
+	/// Tells whether the pool has been dropped.
+	closed: AtomicBool,
+	/// Counters.
+	stats: PoolStats,
+}
+
+impl<T> PoolShared<T> {
+	/// Moves records from the queue to `buf`, without making it exceed the flush threshold.
+	fn drain(&self, buf: &mut Vec<T>) {
+		let mut queue = self.queue.lock().unwrap();
+		let count = FLUSH_THRESHOLD.saturating_sub(buf.len()).min(queue.len());
+		buf.extend(queue.drain(..count));
+	}
+}
+
+/// Rules deciding whether new records are accepted in a pool.
+#[derive(Clone, Copy)]
+struct Admission {
+	/// The maximum number of records in the queue.
+	capacity: usize,
+	/// The policy to apply when the queue is under pressure.
+	policy: DropPolicy,
+	/// With [`DropPolicy::Sample`], the fraction of records to keep.
+	sample_rate: f64,
+}
+
+impl Admission {
+	/// Pushes `record` to `queue`, unless the drop policy discards it.
+	///
+	/// Discarded records are counted in `stats`. If the record has been pushed, the function
+	/// returns `true`.
+	fn push<T>(&self, queue: &mut VecDeque<T>, record: T, stats: &PoolStats) -> bool {
+		let len = queue.len();
+		let accept = match self.policy {
+			DropPolicy::DropNewest => len < self.capacity,
+			DropPolicy::DropOldest => {
+				if len >= self.capacity && queue.pop_front().is_some() {
+					stats.dropped.fetch_add(1, Relaxed);
+				}
+				// A pool without capacity keeps nothing
+				self.capacity > 0
+			}
+			DropPolicy::Sample => {
+				len < self.capacity
+					&& (len < self.capacity / 2 || rand::random_bool(self.sample_rate))
+			}
+		};
+		if !accept {
+			stats.dropped.fetch_add(1, Relaxed);
+			return false;
+		}
+		queue.push_back(record);
+		stats.enqueued.fetch_add(1, Relaxed);
+		true
+	}
+}
+
+/// A pool containing records to be flushed.
+///
+/// The number of records waiting to be flushed is bounded by the configured capacity. When the
+/// pool is under pressure, the configured [`DropPolicy`] applies.
+///
+/// Records are flushed by a task running on the runtime the pool has been created from. When the
+/// pool is dropped, the task makes a last attempt at flushing the remaining records in the
+/// background. [`Pool::shutdown`] allows waiting for it.
+pub struct Pool<T: Record> {
+	shared: Arc<PoolShared<T>>,
+	/// The flushing task.
+	task: Mutex<Option<JoinHandle<()>>>,
+	/// Rules deciding whether new records are accepted.
+	admission: Admission,
+}
+
+impl<T: Record> Pool<T> {
+	/// Creates a new pool
+	pub(crate) fn new() -> Self {
+		let config = Config::get();
+		let shared = Arc::new(PoolShared {
+			queue: Default::default(),
+			notify: Notify::new(),
+			closed: AtomicBool::new(false),
+			stats: PoolStats::default(),
+		});
+		let shared_ = shared.clone();
+		let task = tokio::spawn(async move {
+			let shared = shared_;
+			let mut spool = Self::open_spool().await;
+			let mut interval = interval(Duration::from_secs(10));
+			let mut buf = Vec::with_capacity(FLUSH_THRESHOLD);
+			// Tells whether the last flush failed
+			let mut failed = false;
+			loop {
+				let tick = select! {
+					_ = interval.tick() => true,
+					_ = shared.notify.notified() => false,
+				};
+				// If the pool is closed, stop
+				if shared.closed.load(Acquire) {
+					break;
+				}
+				// Retries only happen on ticks
+				if failed && !tick {
+					continue;
+				}
+				// Spooled records are older, so send them first
+				if tick {
+					if let Some(spool) = &mut spool {
+						Self::replay(&shared.stats, spool).await;
+					}
+				}
+				// Flush as long as the buffer fills up
+				loop {
+					shared.drain(&mut buf);
+					let full = buf.len() >= FLUSH_THRESHOLD;
+					// Unless the buffer is full, wait for the next tick to send records in bulk
+					if !full && !tick {
+						break;
+					}
+					Self::flush(&shared.stats, &mut buf, spool.as_mut()).await;
+					failed = !buf.is_empty();
+					if !full || failed {
+						break;
+					}
+				}
+			}
+			// Attempt to flush remaining records in buffer before stopping
+			loop {
+				shared.drain(&mut buf);
+				if buf.is_empty() {
+					break;
+				}
+				Self::flush(&shared.stats, &mut buf, spool.as_mut()).await;
+				if !buf.is_empty() {
+					let count = buf.len() + shared.queue.lock().unwrap().len();
+					error!(count, "{}: records lost on shutdown", T::ENDPOINT);
+					shared.stats.dropped.fetch_add(count as u64, Relaxed);
+					break;
+				}
+			}
+		});
+		Self {
+			shared,
+			task: Mutex::new(Some(task)),
+			admission: Admission {
+				capacity: config.gateway_pool_capacity,
+				policy: config.gateway_pool_policy,
+				sample_rate: config.gateway_pool_sample_rate,
+			},
+		}
+	}
+
+	/// Opens the on-disk spool, if configured.
+	async fn open_spool() -> Option<Spool> {
+		let config = Config::get();
+		let dir = config.gateway_spool_dir.as_ref()?.join(T::ENDPOINT);
+		match Spool::open(&dir, config.gateway_spool_max_size).await {
+			Ok(spool) => Some(spool),
+			Err(error) => {
+				error!(dir = %dir.display(), %error, "{}: could not open spool", T::ENDPOINT);
+				None
+			}
+		}
+	}
+
+	/// Returns the pool's counters.
+	pub fn stats(&self) -> &PoolStats {
+		&self.shared.stats
+	}
+
+	/// Closes the pool, then waits for the remaining records to be flushed, or spooled if the
+	/// gateway cannot be reached.
+	///
+	/// Records pushed afterwards are never sent.
+	pub async fn shutdown(&self) {
+		self.shared.closed.store(true, Release);
+		self.shared.notify.notify_one();
+		let task = self.task.lock().unwrap().take();
+		if let Some(task) = task {
+			if let Err(error) = task.await {
+				error!(%error, "{}: flushing task failure", T::ENDPOINT);
+			}
+		}
+	}
+
+	/// Pushes new record to the pool.
+	///
+	/// If the pool is under pressure, the record may be discarded according to the drop policy.
+	pub(crate) async fn push(&self, record: T) {
+		let mut queue = self.shared.queue.lock().unwrap();
+		if !self.admission.push(&mut queue, record, &self.shared.stats) {
+			return;
+		}
+		if queue.len() >= FLUSH_THRESHOLD {
+			self.shared.notify.notify_one();
+		}
+	}
+
+	/// Sends the given records to the gateway.
+	///
+	/// If a signing key is configured, the request is signed.
+	///
+	/// On success, the function returns `true`.
+	async fn send(records: &[T]) -> bool {
+		let config = Config::get();
+		let url = format!("{}/{}", config.gateway_url, T::ENDPOINT);
+		let body = match serde_json::to_vec(records) {
+			Ok(body) => body,
+			Err(error) => {
+				error!(%error, "{}: could not serialize records", T::ENDPOINT);
+				return false;
+			}
+		};
+		// HTTP request to push records
+		let client = reqwest::Client::new();
+		let mut request = client
+			.put(&url)
+			.basic_auth(&config.gateway_property, Some(&config.gateway_secret))
+			.header(CONTENT_TYPE, "application/json");
+		if let Some(key) = &config.gateway_signing_key {
+			let Ok(key) = hex::decode(key) else {
+				error!("{}: invalid signing key", T::ENDPOINT);
+				return false;
+			};
+			let timestamp = Utc::now().timestamp();
+			let nonce = hex::encode(rand::random::<[u8; 16]>());
+			let signature = sign::sign(&key, timestamp, &nonce, T::ENDPOINT, &body);
+			request = request
+				.header(sign::TIMESTAMP_HEADER, timestamp)
+				.header(sign::NONCE_HEADER, nonce)
+				.header(sign::SIGNATURE_HEADER, signature);
+		}
+		let res = request.body(body).send().await;
+		let response = match res {
+			Ok(response) => response,
+			Err(error) => {
+				error!(url, %error, "{}: HTTP call failure", T::ENDPOINT);
+				return false;
+			}
+		};
+		let status = response.status();
+		if !status.is_success() {
+			error!(url, %status, "{}: HTTP call failure", T::ENDPOINT);
+			return false;
+		}
+		true
+	}
+
+	/// Sends spooled records, from oldest to newest, until the spool is empty or a failure
+	/// occurs.
+	async fn replay(stats: &PoolStats, spool: &mut Spool) {
+		loop {
+			let (path, batch) = match spool.oldest::<T>().await {
+				Ok(Some(segment)) => segment,
+				Ok(None) => break,
+				Err(error) => {
+					error!(%error, "{}: could not read spool", T::ENDPOINT);
+					break;
+				}
+			};
+			info!(
+				count = batch.len(),
+				"{}: attempt to replay spooled records",
+				T::ENDPOINT
+			);
+			if !Self::send(&batch).await {
+				break;
+			}
+			stats.flushed.fetch_add(batch.len() as u64, Relaxed);
+			if let Err(error) = spool.remove(&path).await {
+				error!(%error, "{}: could not remove spool segment", T::ENDPOINT);
+				break;
+			}
+		}
+	}
+
+	/// Flushes the pool's content, clearing it.
+	///
+	/// If the pool could not be flushed, its content is moved to `spool` if available, or kept
+	/// for a future retry otherwise.
+	/// Records that do not fit in the spool are discarded.
+	async fn flush(stats: &PoolStats, pool: &mut Vec<T>, spool: Option<&mut Spool>) {
+		if pool.is_empty() {
+			return;
+		}
+		info!(
+			count = pool.len(),
+			"{}: attempt to flush records",
+			T::ENDPOINT
+		);
+		if Self::send(pool).await {
+			stats.flushed.fetch_add(pool.len() as u64, Relaxed);
+		} else {
+			let Some(spool) = spool else {
+				stats.retried.fetch_add(pool.len() as u64, Relaxed);
+				return;
+			};
+			match spool.write(pool.as_slice()).await {
+				Ok(Spooled::Written(evicted)) => {
+					stats.retried.fetch_add(pool.len() as u64, Relaxed);
+					stats.dropped.fetch_add(evicted, Relaxed);
+					info!(
+						count = pool.len(),
+						"{}: records moved to spool",
+						T::ENDPOINT
+					);
+				}
+				Ok(Spooled::TooLarge) => {
+					stats.dropped.fetch_add(pool.len() as u64, Relaxed);
+					warn!(
+						count = pool.len(),
+						"{}: records exceed the maximum size of the spool, discarding",
+						T::ENDPOINT
+					);
+				}
+				Err(error) => {
+					stats.retried.fetch_add(pool.len() as u64, Relaxed);
+					error!(%error, "{}: could not write to spool", T::ENDPOINT);
+					return;
+				}
+			}
+		}
+		// The records have been sent, spooled or discarded: clear pool
+		info!("{}: clear pool", T::ENDPOINT);
+		pool.clear();
+	}
+}
+
+impl<T: Record> Drop for Pool<T> {
+	fn drop(&mut self) {
+		self.shared.closed.store(true, Release);
+		self.shared.notify.notify_one();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn fill(admission: Admission, count: u32) -> (VecDeque<u32>, PoolStats) {
+		let mut queue = VecDeque::new();
+		let stats = PoolStats::default();
+		for i in 0..count {
+			admission.push(&mut queue, i, &stats);
+		}
+		(queue, stats)
+	}
+
+	#[test]
+	fn drop_newest() {
+		let admission = Admission {
+			capacity: 4,
+			policy: DropPolicy::DropNewest,
+			sample_rate: 1.,
+		};
+		let (queue, stats) = fill(admission, 6);
+		assert_eq!(queue, [0, 1, 2, 3]);
+		assert_eq!(stats.enqueued(), 4);
+		assert_eq!(stats.dropped(), 2);
+	}
+
+	#[test]
+	fn drop_oldest() {
+		let admission = Admission {
+			capacity: 4,
+			policy: DropPolicy::DropOldest,
+			sample_rate: 1.,
+		};
+		let (queue, stats) = fill(admission, 6);
+		assert_eq!(queue, [2, 3, 4, 5]);
+		assert_eq!(stats.enqueued(), 6);
+		assert_eq!(stats.dropped(), 2);
+	}
+
+	#[test]
+	fn drop_oldest_without_capacity() {
+		let admission = Admission {
+			capacity: 0,
+			policy: DropPolicy::DropOldest,
+			sample_rate: 1.,
+		};
+		let (queue, stats) = fill(admission, 2);
+		assert!(queue.is_empty());
+		assert_eq!(stats.enqueued(), 0);
+		assert_eq!(stats.dropped(), 2);
+	}
+
+	#[test]
+	fn sample() {
+		// Once half full, no record is kept
+		let admission = Admission {
+			capacity: 4,
+			policy: DropPolicy::Sample,
+			sample_rate: 0.,
+		};
+		let (queue, stats) = fill(admission, 6);
+		assert_eq!(queue, [0, 1]);
+		assert_eq!(stats.dropped(), 4);
+		// Once half full, every record is kept until the pool is full
+		let admission = Admission {
+			sample_rate: 1.,
+			..admission
+		};
+		let (queue, stats) = fill(admission, 6);
+		assert_eq!(queue, [0, 1, 2, 3]);
+		assert_eq!(stats.dropped(), 2);
+	}
+}
@@ -0,0 +1,317 @@
+//! Scrubbing of personal data from URIs.
+
+use regex::Regex;
+use std::{borrow::Cow, sync::OnceLock};
+
+/// The text replacing scrubbed data.
+pub const REDACTED: &str = "REDACTED";
+
+/// Names of query parameters that are sensitive by default.
+const DEFAULT_PARAMS: &[&str] = &[
+	"auth",
+	"code",
+	"email",
+	"key",
+	"password",
+	"secret",
+	"session",
+	"sessionid",
+	"sid",
+	"token",
+];
+
+/// Patterns of sensitive data, wherever they appear in a URI.
+fn default_patterns() -> &'static [Regex] {
+	static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+	PATTERNS.get_or_init(|| {
+		vec![
+			// Email addresses, with `@` possibly percent-encoded
+			Regex::new(r"[A-Za-z0-9._%+-]+(?:@|%40)[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap(),
+			// UUID-like tokens, with or without hyphens
+			Regex::new(
+				r"(?i)\b[0-9a-f]{8}-?[0-9a-f]{4}-?[0-9a-f]{4}-?[0-9a-f]{4}-?[0-9a-f]{12}\b",
+			)
+			.unwrap(),
+		]
+	})
+}
+
+/// What to do with sensitive query parameters.
+#[derive(Clone, Copy, Default)]
+pub enum ScrubAction {
+	/// Replace the parameter's value with [`REDACTED`].
+	#[default]
+	Redact,
+	/// Remove the parameter.
+	Remove,
+}
+
+/// Scrubs personal data from URIs (such as the URI or referer of an access) before storage.
+///
+/// Sensitive query parameters are matched by name, case-insensitively. Sensitive patterns are
+/// replaced with [`REDACTED`] anywhere in the URI.
+///
+/// By default, common parameters carrying credentials or tokens, email addresses and UUID-like
+/// tokens are considered sensitive.
+#[derive(Clone)]
+pub struct Scrubber {
+	/// Names of sensitive query parameters.
+	params: Vec<String>,
+	/// Patterns of sensitive data.
+	patterns: Vec<Regex>,
+	/// What to do with sensitive query parameters.
+	action: ScrubAction,
+}
+
+impl Default for Scrubber {
+	fn default() -> Self {
+		Self {
+			params: DEFAULT_PARAMS.iter().map(|s| s.to_string()).collect(),
+			patterns: default_patterns().to_vec(),
+			action: ScrubAction::default(),
+		}
+	}
+}
+
+impl Scrubber {
+	/// Returns a scrubber without any sensitive parameter or pattern.
+	pub fn empty() -> Self {
+		Self {
+			params: vec![],
+			patterns: vec![],
+			action: ScrubAction::default(),
+		}
+	}
+
+	/// Considers query parameters named `name` sensitive.
+	pub fn param(mut self, name: impl Into<String>) -> Self {
+		self.params.push(name.into());
+		self
+	}
+
+	/// Considers data matching `pattern` sensitive.
+	pub fn pattern(mut self, pattern: Regex) -> Self {
+		self.patterns.push(pattern);
+		self
+	}
+
+	/// Sets what to do with sensitive query parameters.
+	pub fn action(mut self, action: ScrubAction) -> Self {
+		self.action = action;
+		self
+	}
+
+	/// Tells whether the query parameter with the given name is sensitive.
+	///
+	/// The name is percent-decoded first, so that encoding it does not evade scrubbing.
+	fn is_sensitive(&self, name: &str) -> bool {
+		let name = decode(name);
+		self.params
+			.iter()
+			.any(|param| param.eq_ignore_ascii_case(&name))
+	}
+
+	/// Scrubs sensitive parameters from `params`, a list of `name=value` pairs separated by
+	/// `&`.
+	fn scrub_params(&self, params: &str) -> String {
+		let pairs: Vec<Cow<str>> = params
+			.split('&')
+			.filter_map(|pair| {
+				let name = pair.split('=').next().unwrap_or_default();
+				if !self.is_sensitive(name) {
+					return Some(Cow::Borrowed(pair));
+				}
+				match self.action {
+					ScrubAction::Redact => Some(Cow::Owned(format!("{name}={REDACTED}"))),
+					ScrubAction::Remove => None,
+				}
+			})
+			.collect();
+		pairs.join("&")
+	}
+
+	/// Returns `uri` without its sensitive data.
+	///
+	/// Fragments made of parameters (such as `#access_token=...`) are scrubbed like the query.
+	pub fn scrub<'u>(&self, uri: &'u str) -> Cow<'u, str> {
+		let (rest, fragment) = match uri.split_once('#') {
+			Some((rest, fragment)) => (rest, Some(fragment)),
+			None => (uri, None),
+		};
+		let (path, query) = match rest.split_once('?') {
+			Some((path, query)) => (path, Some(query)),
+			None => (rest, None),
+		};
+		let mut scrubbed = String::with_capacity(uri.len());
+		scrubbed.push_str(path);
+		if let Some(query) = query {
+			let query = self.scrub_params(query);
+			if !query.is_empty() {
+				scrubbed.push('?');
+				scrubbed.push_str(&query);
+			}
+		}
+		if let Some(fragment) = fragment {
+			if !fragment.contains('=') {
+				scrubbed.push('#');
+				scrubbed.push_str(fragment);
+			} else {
+				let fragment = self.scrub_params(fragment);
+				if !fragment.is_empty() {
+					scrubbed.push('#');
+					scrubbed.push_str(&fragment);
+				}
+			}
+		}
+		for pattern in &self.patterns {
+			if let Cow::Owned(s) = pattern.replace_all(&scrubbed, REDACTED) {
+				scrubbed = s;
+			}
+		}
+		if scrubbed == uri {
+			Cow::Borrowed(uri)
+		} else {
+			Cow::Owned(scrubbed)
+		}
+	}
+}
+
+/// Decodes a percent-encoded query parameter name, where `+` stands for a space.
+///
+/// Invalid escapes are kept as is.
+fn decode(name: &str) -> Cow<'_, str> {
+	if !name.contains(['%', '+']) {
+		return Cow::Borrowed(name);
+	}
+	let bytes = name.as_bytes();
+	let mut decoded = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		let hex = bytes
+			.get(i + 1..i + 3)
+			.and_then(|hex| std::str::from_utf8(hex).ok())
+			.and_then(|hex| u8::from_str_radix(hex, 16).ok());
+		match (bytes[i], hex) {
+			(b'%', Some(byte)) => {
+				decoded.push(byte);
+				i += 3;
+			}
+			(b'+', _) => {
+				decoded.push(b' ');
+				i += 1;
+			}
+			(byte, _) => {
+				decoded.push(byte);
+				i += 1;
+			}
+		}
+	}
+	Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn untouched() {
+		let scrubber = Scrubber::default();
+		for uri in [
+			"/",
+			"/blog/post?page=2&sort=date",
+			"/docs#install",
+			"/a?b",
+			"/c#",
+		] {
+			assert!(matches!(scrubber.scrub(uri), Cow::Borrowed(_)), "{uri}");
+		}
+	}
+
+	#[test]
+	fn redact() {
+		let scrubber = Scrubber::default();
+		assert_eq!(
+			scrubber.scrub("/reset?token=abc&page=2"),
+			"/reset?token=REDACTED&page=2"
+		);
+		// Names are case-insensitive
+		assert_eq!(
+			scrubber.scrub("/login?Password=x"),
+			"/login?Password=REDACTED"
+		);
+		// Parameters without value
+		assert_eq!(scrubber.scrub("/a?secret"), "/a?secret=REDACTED");
+	}
+
+	#[test]
+	fn remove() {
+		let scrubber = Scrubber::default().action(ScrubAction::Remove);
+		assert_eq!(scrubber.scrub("/reset?token=abc&page=2"), "/reset?page=2");
+		assert_eq!(scrubber.scrub("/reset?token=abc"), "/reset");
+	}
+
+	#[test]
+	fn encoded_names() {
+		let scrubber = Scrubber::default();
+		assert_eq!(scrubber.scrub("/a?%74oken=abc"), "/a?%74oken=REDACTED");
+		assert_eq!(scrubber.scrub("/a?e%6Dail=x"), "/a?e%6Dail=REDACTED");
+		assert_eq!(scrubber.scrub("/a?%53ID=x"), "/a?%53ID=REDACTED");
+		let scrubber = Scrubber::empty().param("api key");
+		assert_eq!(scrubber.scrub("/a?api+key=x"), "/a?api+key=REDACTED");
+		assert_eq!(scrubber.scrub("/a?api%20key=x"), "/a?api%20key=REDACTED");
+		// Invalid escapes do not match
+		assert_eq!(scrubber.scrub("/a?api%2key=x"), "/a?api%2key=x");
+	}
+
+	#[test]
+	fn fragments() {
+		let scrubber = Scrubber::default();
+		assert_eq!(
+			scrubber.scrub("/callback?state=1#access_token=abc&token=def"),
+			"/callback?state=1#access_token=abc&token=REDACTED"
+		);
+		assert_eq!(
+			scrubber.scrub("/docs?token=abc#install"),
+			"/docs?token=REDACTED#install"
+		);
+		let scrubber = scrubber.action(ScrubAction::Remove);
+		assert_eq!(scrubber.scrub("/callback#token=def"), "/callback");
+	}
+
+	#[test]
+	fn emails() {
+		let scrubber = Scrubber::default();
+		assert_eq!(scrubber.scrub("/u/john.doe@example.com"), "/u/REDACTED");
+		assert_eq!(scrubber.scrub("/u?to=john%40example.org"), "/u?to=REDACTED");
+		assert_eq!(
+			scrubber.scrub("/docs#contact=a@b.io"),
+			"/docs#contact=REDACTED"
+		);
+	}
+
+	#[test]
+	fn uuids() {
+		let scrubber = Scrubber::default();
+		assert_eq!(
+			scrubber.scrub("/unsubscribe/3F2504E0-4F89-11D3-9A0C-0305E82C3301"),
+			"/unsubscribe/REDACTED"
+		);
+		assert_eq!(
+			scrubber.scrub("/c?id=3f2504e04f8911d39a0c0305e82c3301"),
+			"/c?id=REDACTED"
+		);
+		// Shorter hexadecimal strings are kept
+		assert_eq!(scrubber.scrub("/commit/3f2504e0"), "/commit/3f2504e0");
+	}
+
+	#[test]
+	fn custom() {
+		let scrubber = Scrubber::empty()
+			.param("ref")
+			.pattern(Regex::new(r"\d{16}").unwrap());
+		assert_eq!(
+			scrubber.scrub("/pay/4111111111111111?ref=x&token=y"),
+			"/pay/REDACTED?ref=REDACTED&token=y"
+		);
+	}
+}
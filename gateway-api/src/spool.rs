@@ -0,0 +1,226 @@
+//! On-disk spool, keeping data that could not be sent to the gateway.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+	io,
+	path::{Path, PathBuf},
+	time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::fs;
+use tracing::warn;
+
+/// The extension of segment files.
+const SEGMENT_EXTENSION: &str = "jsonl";
+
+/// The outcome of writing a batch to the spool.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Spooled {
+	/// The batch has been written, after discarding the given number of older items to respect
+	/// the maximum size of the spool.
+	Written(u64),
+	/// The batch alone exceeds the maximum size of the spool, so it has been discarded.
+	TooLarge,
+}
+
+/// An append-only spool of batches, stored in a directory.
+///
+/// Each batch is written to its own segment file, as JSON lines. Segments are named after their
+/// creation date so that they are replayed in the order they were written.
+///
+/// When writing a batch would make the spool exceed its maximum size, the oldest segments are
+/// discarded.
+pub struct Spool {
+	/// The directory containing the segments.
+	dir: PathBuf,
+	/// The maximum total size of segments, in bytes.
+	max_size: u64,
+	/// Counter making segment names unique when written within the same millisecond.
+	seq: u64,
+}
+
+impl Spool {
+	/// Opens the spool in the given directory, creating it if it does not exist.
+	pub async fn open(dir: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+		let dir = dir.into();
+		fs::create_dir_all(&dir).await?;
+		Ok(Self {
+			dir,
+			max_size,
+			seq: 0,
+		})
+	}
+
+	/// Returns the paths of all segments with their respective sizes, from oldest to newest.
+	async fn segments(&self) -> io::Result<Vec<(PathBuf, u64)>> {
+		let mut segments = vec![];
+		let mut entries = fs::read_dir(&self.dir).await?;
+		while let Some(entry) = entries.next_entry().await? {
+			let path = entry.path();
+			if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+				continue;
+			}
+			let len = entry.metadata().await?.len();
+			segments.push((path, len));
+		}
+		segments.sort_unstable();
+		Ok(segments)
+	}
+
+	/// Writes a batch to a new segment.
+	pub async fn write<T: Serialize>(&mut self, batch: &[T]) -> io::Result<Spooled> {
+		let mut data = vec![];
+		for item in batch {
+			serde_json::to_writer(&mut data, item)?;
+			data.push(b'\n');
+		}
+		let len = data.len() as u64;
+		if len > self.max_size {
+			return Ok(Spooled::TooLarge);
+		}
+		// Make room for the new segment
+		let segments = self.segments().await?;
+		let mut total: u64 = segments.iter().map(|(_, len)| len).sum();
+		let mut evicted = 0;
+		for (path, segment_len) in segments {
+			if total + len <= self.max_size {
+				break;
+			}
+			warn!(path = %path.display(), "spool: full, discarding oldest segment");
+			let segment = fs::read(&path).await?;
+			evicted += segment.iter().filter(|b| **b == b'\n').count() as u64;
+			fs::remove_file(&path).await?;
+			total -= segment_len;
+		}
+		// Write to a temporary file first so that a partial segment is never replayed
+		let timestamp = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_millis();
+		let name = format!("{timestamp:020}-{:010}", self.seq);
+		self.seq = self.seq.wrapping_add(1);
+		let tmp_path = self.dir.join(format!("{name}.tmp"));
+		fs::write(&tmp_path, data).await?;
+		let path = self.dir.join(format!("{name}.{SEGMENT_EXTENSION}"));
+		fs::rename(tmp_path, path).await?;
+		Ok(Spooled::Written(evicted))
+	}
+
+	/// Returns the oldest segment along with its path, if any.
+	///
+	/// Segments that cannot be parsed are discarded.
+	pub async fn oldest<T: DeserializeOwned>(&self) -> io::Result<Option<(PathBuf, Vec<T>)>> {
+		for (path, _) in self.segments().await? {
+			let data = fs::read(&path).await?;
+			let batch = data
+				.split(|b| *b == b'\n')
+				.filter(|line| !line.is_empty())
+				.map(serde_json::from_slice)
+				.collect::<Result<Vec<T>, _>>();
+			match batch {
+				Ok(batch) => return Ok(Some((path, batch))),
+				Err(error) => {
+					warn!(path = %path.display(), %error, "spool: invalid segment, discarding");
+					fs::remove_file(&path).await?;
+				}
+			}
+		}
+		Ok(None)
+	}
+
+	/// Removes the segment at the given path, once it has been sent.
+	pub async fn remove(&self, path: &Path) -> io::Result<()> {
+		fs::remove_file(path).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempfile::TempDir;
+
+	async fn files(dir: &Path) -> Vec<String> {
+		let mut names = vec![];
+		let mut entries = fs::read_dir(dir).await.unwrap();
+		while let Some(entry) = entries.next_entry().await.unwrap() {
+			names.push(entry.file_name().into_string().unwrap());
+		}
+		names.sort_unstable();
+		names
+	}
+
+	#[tokio::test]
+	async fn write_and_replay() {
+		let dir = TempDir::new().unwrap();
+		let mut spool = Spool::open(dir.path().join("access"), 1024).await.unwrap();
+		let res = spool.write(&[1, 2, 3]).await.unwrap();
+		assert_eq!(res, Spooled::Written(0));
+		let res = spool.write(&[4]).await.unwrap();
+		assert_eq!(res, Spooled::Written(0));
+		// Only complete segments remain
+		let names = files(&dir.path().join("access")).await;
+		assert_eq!(names.len(), 2);
+		assert!(names.iter().all(|name| name.ends_with(".jsonl")));
+		// Segments are replayed from oldest to newest
+		let (path, batch) = spool.oldest::<u32>().await.unwrap().unwrap();
+		assert_eq!(batch, [1, 2, 3]);
+		spool.remove(&path).await.unwrap();
+		let (path, batch) = spool.oldest::<u32>().await.unwrap().unwrap();
+		assert_eq!(batch, [4]);
+		spool.remove(&path).await.unwrap();
+		assert!(spool.oldest::<u32>().await.unwrap().is_none());
+	}
+
+	#[tokio::test]
+	async fn replay_after_reopen() {
+		let dir = TempDir::new().unwrap();
+		let mut spool = Spool::open(dir.path(), 1024).await.unwrap();
+		spool.write(&["a", "b"]).await.unwrap();
+		drop(spool);
+		let spool = Spool::open(dir.path(), 1024).await.unwrap();
+		let (_, batch) = spool.oldest::<String>().await.unwrap().unwrap();
+		assert_eq!(batch, ["a", "b"]);
+	}
+
+	#[tokio::test]
+	async fn evict_oldest() {
+		let dir = TempDir::new().unwrap();
+		// Each batch of two single-digit numbers takes 4 bytes
+		let mut spool = Spool::open(dir.path(), 10).await.unwrap();
+		spool.write(&[1, 2]).await.unwrap();
+		spool.write(&[3, 4]).await.unwrap();
+		let res = spool.write(&[5, 6]).await.unwrap();
+		assert_eq!(res, Spooled::Written(2));
+		let (path, batch) = spool.oldest::<u32>().await.unwrap().unwrap();
+		assert_eq!(batch, [3, 4]);
+		spool.remove(&path).await.unwrap();
+		let (_, batch) = spool.oldest::<u32>().await.unwrap().unwrap();
+		assert_eq!(batch, [5, 6]);
+	}
+
+	#[tokio::test]
+	async fn too_large() {
+		let dir = TempDir::new().unwrap();
+		let mut spool = Spool::open(dir.path(), 4).await.unwrap();
+		spool.write(&[1, 2]).await.unwrap();
+		let res = spool.write(&[3, 4, 5]).await.unwrap();
+		assert_eq!(res, Spooled::TooLarge);
+		// Existing segments are kept
+		let (_, batch) = spool.oldest::<u32>().await.unwrap().unwrap();
+		assert_eq!(batch, [1, 2]);
+	}
+
+	#[tokio::test]
+	async fn skip_partial_and_invalid_segments() {
+		let dir = TempDir::new().unwrap();
+		let mut spool = Spool::open(dir.path(), 1024).await.unwrap();
+		// A segment interrupted while being written
+		fs::write(dir.path().join("0.tmp"), "1\n2").await.unwrap();
+		// A corrupted segment, older than the next one
+		fs::write(dir.path().join("0.jsonl"), "1\n{").await.unwrap();
+		spool.write(&[3]).await.unwrap();
+		let (_, batch) = spool.oldest::<u32>().await.unwrap().unwrap();
+		assert_eq!(batch, [3]);
+		let names = files(dir.path()).await;
+		assert!(!names.contains(&"0.jsonl".to_owned()));
+	}
+}
@@ -1,11 +1,43 @@
 CREATE TABLE IF NOT EXISTS property (
     uuid UUID PRIMARY KEY,
     name VARCHAR(64) NOT NULL,
-    secret UUID NOT NULL
+    -- Plaintext secret from before secrets were hashed, moved to `property_secret` at startup
+    secret UUID,
+    origins TEXT[] NOT NULL DEFAULT '{}',
+    hosts TEXT[] NOT NULL DEFAULT '{}',
+    rate_limit TEXT,
+    monthly_quota BIGINT,
+    store_raw BOOLEAN NOT NULL DEFAULT FALSE,
+    signing_key BYTEA,
+    retention_anonymize_days INTEGER DEFAULT 365,
+    retention_raw_days INTEGER,
+    retention_aggregate_days INTEGER
 );
+-- Upgrade of databases created before the following columns were added
+ALTER TABLE property ADD COLUMN IF NOT EXISTS origins TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE property ADD COLUMN IF NOT EXISTS store_raw BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE property ADD COLUMN IF NOT EXISTS retention_anonymize_days INTEGER DEFAULT 365;
+ALTER TABLE property ADD COLUMN IF NOT EXISTS retention_raw_days INTEGER;
+ALTER TABLE property ADD COLUMN IF NOT EXISTS retention_aggregate_days INTEGER;
+ALTER TABLE property ADD COLUMN IF NOT EXISTS signing_key BYTEA;
+ALTER TABLE property ADD COLUMN IF NOT EXISTS hosts TEXT[] NOT NULL DEFAULT '{}';
+ALTER TABLE property ADD COLUMN IF NOT EXISTS rate_limit TEXT;
+ALTER TABLE property ADD COLUMN IF NOT EXISTS monthly_quota BIGINT;
+-- Upgrade of databases created before secrets were hashed
+ALTER TABLE property ALTER COLUMN secret DROP NOT NULL;
+
+CREATE TABLE IF NOT EXISTS property_secret (
+    id UUID PRIMARY KEY,
+    property UUID NOT NULL,
+    hash TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS property_secret_property ON property_secret(property);
 
 CREATE TABLE IF NOT EXISTS analytics (
     property UUID NOT NULL,
+    id UUID NOT NULL,
     date TIMESTAMP NOT NULL,
     peer_addr INET,
     user_agent TEXT,
@@ -14,10 +46,77 @@ CREATE TABLE IF NOT EXISTS analytics (
     device JSON,
     method TEXT NOT NULL,
     uri TEXT NOT NULL,
-    UNIQUE (peer_addr, user_agent, method, uri)
+    host TEXT,
+    status SMALLINT,
+    latency INTEGER,
+    response_size BIGINT,
+    screen_width INTEGER,
+    screen_height INTEGER,
+    opt_out BOOLEAN NOT NULL DEFAULT FALSE,
+    visitor_id BYTEA,
+    session UUID,
+    is_bot BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (property, id)
+);
+-- Upgrade of databases created before the following columns were added
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS status SMALLINT;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS latency INTEGER;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS response_size BIGINT;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS session UUID;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS is_bot BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS screen_width INTEGER;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS screen_height INTEGER;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS visitor_id BYTEA;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS opt_out BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS host TEXT;
+-- Upgrade of databases created before accesses were deduplicated on their ID
+ALTER TABLE analytics ADD COLUMN IF NOT EXISTS id UUID NOT NULL DEFAULT gen_random_uuid();
+ALTER TABLE analytics ALTER COLUMN id DROP DEFAULT;
+ALTER TABLE analytics DROP CONSTRAINT IF EXISTS analytics_peer_addr_user_agent_method_uri_key;
+DROP INDEX IF EXISTS raw_info;
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conrelid = 'analytics'::regclass AND contype = 'p') THEN
+        ALTER TABLE analytics ADD PRIMARY KEY (property, id);
+    END IF;
+END
+$$;
+CREATE INDEX IF NOT EXISTS date ON analytics(date);
+CREATE INDEX IF NOT EXISTS session ON analytics(session);
+
+CREATE TABLE IF NOT EXISTS analytics_daily (
+    property UUID NOT NULL,
+    day DATE NOT NULL,
+    is_bot BOOLEAN NOT NULL,
+    views BIGINT NOT NULL,
+    visitors BIGINT NOT NULL,
+    opted_out BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (property, day, is_bot)
+);
+
+CREATE TABLE IF NOT EXISTS visitor_salt (
+    day DATE PRIMARY KEY,
+    salt BYTEA NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS visitor_session (
+    property UUID NOT NULL,
+    visitor_id BYTEA NOT NULL,
+    session UUID NOT NULL,
+    last_seen TIMESTAMP NOT NULL,
+    is_bot BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY (property, visitor_id)
+);
+
+CREATE TABLE IF NOT EXISTS event (
+    property UUID NOT NULL,
+    id UUID NOT NULL,
+    date TIMESTAMP NOT NULL,
+    name TEXT NOT NULL,
+    properties JSONB NOT NULL,
+    PRIMARY KEY (property, id)
 );
-CREATE INDEX date ON analytics(date);
-CREATE UNIQUE INDEX raw_info ON analytics(peer_addr, user_agent, method, uri);
+CREATE INDEX IF NOT EXISTS event_date ON event(date);
 
 CREATE TABLE IF NOT EXISTS newsletter_subscriber (
     email TEXT PRIMARY KEY,
@@ -26,3 +125,24 @@ CREATE TABLE IF NOT EXISTS newsletter_subscriber (
     unsubscribe_token UUID,
     UNIQUE (email)
 );
+
+CREATE TABLE IF NOT EXISTS property_usage (
+    property UUID NOT NULL,
+    month DATE NOT NULL,
+    records BIGINT NOT NULL,
+    PRIMARY KEY (property, month)
+);
+
+CREATE TABLE IF NOT EXISTS newsletter_pending (
+    email TEXT PRIMARY KEY,
+    token UUID NOT NULL UNIQUE,
+    request_date TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS audit_log (
+    id UUID PRIMARY KEY,
+    date TIMESTAMP NOT NULL,
+    action TEXT NOT NULL,
+    details JSONB NOT NULL
+);
@@ -0,0 +1,258 @@
+//! Command line tool to operate the gateway.
+//!
+//! It uses the same configuration as the HTTP server.
+
+use anyhow::{Context as _, Result, bail};
+use chrono::{DateTime, TimeDelta, Utc};
+use clap::{Parser, Subcommand};
+use gateway::{
+	Config,
+	service::{analytics, audit, newsletter, property, retention, secret},
+};
+use gateway_api::util::date_format;
+use serde_json::json;
+use std::io::{self, Write};
+use tokio_postgres::NoTls;
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(Parser)]
+#[command(about = "Operates the gateway")]
+struct Args {
+	#[command(subcommand)]
+	command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+	/// Creates the database's tables, if they do not exist.
+	Schema,
+	/// Manages properties.
+	#[command(subcommand)]
+	Property(PropertyCommand),
+	/// Manages the secrets of a property.
+	#[command(subcommand)]
+	Secret(SecretCommand),
+	/// Renews the server's downloaded resources (UaParser, GeoIP and crawlers list).
+	///
+	/// The server must be running, with an admin token.
+	Renew,
+	/// Exports the accesses of a property as CSV, to the standard output.
+	Export {
+		/// The property's UUID.
+		property: Uuid,
+		/// The beginning of the range, as RFC 3339.
+		#[arg(long)]
+		from: DateTime<Utc>,
+		/// The end of the range (excluded), as RFC 3339.
+		#[arg(long)]
+		to: DateTime<Utc>,
+	},
+	/// Applies the retention policies of all properties, including anonymization.
+	Anonymize,
+	/// Lists current newsletter subscribers.
+	Subscribers,
+}
+
+#[derive(Subcommand)]
+enum PropertyCommand {
+	/// Creates a property along with its first secret.
+	Create {
+		/// The property's name.
+		name: String,
+		/// An origin allowed to submit data from browsers. May be repeated.
+		#[arg(long = "origin")]
+		origins: Vec<String>,
+		/// A host accesses may be sent to, possibly starting with `*.`. May be repeated.
+		///
+		/// If none is given, any host is allowed.
+		#[arg(long = "host")]
+		hosts: Vec<String>,
+		/// Stores raw IP addresses and user agents.
+		#[arg(long)]
+		store_raw: bool,
+	},
+	/// Lists properties.
+	List,
+}
+
+#[derive(Subcommand)]
+enum SecretCommand {
+	/// Lists the secrets of a property.
+	List {
+		/// The property's UUID.
+		property: Uuid,
+	},
+	/// Creates a new secret for a property. Other secrets remain active.
+	Create {
+		/// The property's UUID.
+		property: Uuid,
+	},
+	/// Revokes a secret of a property.
+	Revoke {
+		/// The property's UUID.
+		property: Uuid,
+		/// The secret's identifier.
+		id: Uuid,
+		/// The number of seconds during which the secret remains active.
+		#[arg(long, default_value_t = 0)]
+		grace: u32,
+	},
+}
+
+/// Connects to the database, driving the connection in a background task.
+async fn connect(config: &Config) -> Result<tokio_postgres::Client> {
+	let (client, connection) = tokio_postgres::connect(&config.db, NoTls)
+		.await
+		.context("database connection")?;
+	tokio::spawn(async move {
+		if let Err(error) = connection.await {
+			error!(%error, "database connection error");
+		}
+	});
+	Ok(client)
+}
+
+/// Asks the running server to renew its resources.
+async fn renew(config: &Config) -> Result<()> {
+	let Some(token) = &config.admin_token else {
+		bail!("`ADMIN_TOKEN` is not set");
+	};
+	let url = format!("http://127.0.0.1:{}/admin/renew", config.port);
+	let response = reqwest::Client::new()
+		.post(&url)
+		.bearer_auth(token)
+		.send()
+		.await
+		.context("could not reach server")?;
+	let status = response.status();
+	if !status.is_success() {
+		let body = response.text().await.unwrap_or_default();
+		bail!("renewal failure (status {}): {body}", status.as_u16());
+	}
+	Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+	tracing_subscriber::fmt().with_writer(io::stderr).init();
+	let args = Args::parse();
+	let config = envy::from_env::<Config>().context("invalid configuration")?;
+	let mut db = connect(&config).await?;
+	let mut stdout = io::stdout().lock();
+	match args.command {
+		Command::Schema => {
+			db.batch_execute(include_str!("../../schema.sql")).await?;
+			eprintln!("schema applied");
+		}
+		Command::Property(PropertyCommand::Create {
+			name,
+			origins,
+			hosts,
+			store_raw,
+		}) => {
+			if !property::validate_name(&name) {
+				bail!("invalid name");
+			}
+			let tx = db.transaction().await?;
+			let uuid = property::create(&tx, &name, &origins, &hosts, store_raw).await?;
+			let (id, secret) = secret::create(&tx, &uuid)
+				.await?
+				.context("property vanished before its secret was created")?;
+			let details = json!({
+				"property": uuid,
+				"secret": id,
+			});
+			audit::record(&tx, "property_create", &details).await?;
+			tx.commit().await?;
+			writeln!(stdout, "property: {uuid}")?;
+			writeln!(stdout, "secret: {secret} (id: {id})")?;
+		}
+		Command::Property(PropertyCommand::List) => {
+			for property in property::list(&db).await? {
+				writeln!(stdout, "{}\t{}", property.uuid, property.name)?;
+			}
+		}
+		Command::Secret(SecretCommand::List {
+			property,
+		}) => {
+			for secret in secret::list(&db, &property).await? {
+				let expires_at = secret
+					.expires_at
+					.map(|date| date.format(date_format::FORMAT).to_string())
+					.unwrap_or_else(|| "never".to_owned());
+				writeln!(
+					stdout,
+					"{}\tcreated: {}\texpires: {expires_at}",
+					secret.id,
+					secret.created_at.format(date_format::FORMAT)
+				)?;
+			}
+		}
+		Command::Secret(SecretCommand::Create {
+			property,
+		}) => {
+			let tx = db.transaction().await?;
+			let Some((id, secret)) = secret::create(&tx, &property).await? else {
+				bail!("property not found");
+			};
+			let details = json!({
+				"property": property,
+				"secret": id,
+			});
+			audit::record(&tx, "secret_create", &details).await?;
+			tx.commit().await?;
+			writeln!(stdout, "secret: {secret} (id: {id})")?;
+		}
+		Command::Secret(SecretCommand::Revoke {
+			property,
+			id,
+			grace,
+		}) => {
+			let delta = TimeDelta::seconds(grace as _);
+			let tx = db.transaction().await?;
+			if !secret::revoke(&tx, &property, &id, delta).await? {
+				bail!("secret not found");
+			}
+			let details = json!({
+				"property": property,
+				"secret": id,
+				"grace": grace,
+			});
+			audit::record(&tx, "secret_revoke", &details).await?;
+			tx.commit().await?;
+			eprintln!("secret revoked");
+		}
+		Command::Renew => {
+			renew(&config).await?;
+			eprintln!("resources renewed");
+		}
+		Command::Export {
+			property,
+			from,
+			to,
+		} => {
+			let csv = analytics::export_csv(&db, &property, &from, &to).await?;
+			stdout.write_all(csv.as_bytes())?;
+		}
+		Command::Anonymize => {
+			for policy in retention::policies(&db).await? {
+				policy
+					.apply(&mut db)
+					.await
+					.with_context(|| format!("retention policy of {}", policy.property))?;
+			}
+			eprintln!("retention policies applied");
+		}
+		Command::Subscribers => {
+			for (email, subscribe_date) in newsletter::list_subscribers(&db).await? {
+				writeln!(
+					stdout,
+					"{email}\t{}",
+					subscribe_date.format(date_format::FORMAT)
+				)?;
+			}
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,132 @@
+//! Data collection and authentication service.
+//!
+//! The library is shared by the HTTP server (`gateway`) and the administration CLI
+//! (`gateway-admin`).
+
+pub mod route;
+pub mod service;
+pub mod util;
+
+use crate::{
+	service::{
+		crawler::CrawlerList,
+		geoip::GeoIP,
+		mailer::Mailer,
+		property::OriginCache,
+		rate_limit::{Policy, PropertyLimiter},
+		secret::AuthCache,
+		signature::NonceCache,
+		uaparser::UaParser,
+	},
+	util::{ConnectionPool, Renewer},
+};
+use gateway_api::{
+	client_ip::{ClientIpResolver, IpHeader},
+	scrub::Scrubber,
+};
+use ipnet::IpNet;
+use reqwest::Url;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+/// The service's configuration, read from the environment.
+#[derive(Deserialize)]
+pub struct Config {
+	/// The port the server listens to.
+	pub port: u32,
+	/// The connection string to the database.
+	pub db: String,
+	/// The URL to fetch uaparser data.
+	pub uaparser_url: String,
+	/// The URL to fetch geoip data.
+	pub geoip_url: String,
+	/// The geoip username (account ID).
+	pub geoip_user: String,
+	/// The geoip password (license key).
+	pub geoip_password: String,
+	/// The URL to fetch the list of known crawlers.
+	pub crawler_url: String,
+	/// The token to access administration endpoints. If not set, they are disabled.
+	pub admin_token: Option<String>,
+	/// Names of query parameters to scrub from URIs and referers, in addition to the default
+	/// ones.
+	#[serde(default)]
+	pub scrub_params: Vec<String>,
+	/// The rate limit of each peer, on routes without a specific policy.
+	#[serde(default = "default_rate_limit")]
+	pub rate_limit: Policy,
+	/// The rate limit of each peer, on routes pushing data with a property's secret.
+	#[serde(default = "default_ingest_rate_limit")]
+	pub ingest_rate_limit: Policy,
+	/// The rate limit of each peer, on newsletter routes.
+	#[serde(default = "default_newsletter_rate_limit")]
+	pub newsletter_rate_limit: Policy,
+	/// The networks of trusted reverse proxies, whose headers are used to resolve the address
+	/// of clients.
+	#[serde(default)]
+	pub trusted_proxies: Vec<IpNet>,
+	/// The headers passing the address of clients, in order of precedence. If empty, the
+	/// default order applies.
+	#[serde(default)]
+	pub client_ip_headers: Vec<IpHeader>,
+	/// The URL of the page confirming newsletter subscriptions, which receives the confirmation
+	/// token in the `token` query parameter. If not set, subscriptions are refused.
+	pub newsletter_confirm_url: Option<String>,
+	/// The URL of the webhook in charge of delivering emails. If not set, emails are written to
+	/// logs.
+	pub mailer_webhook_url: Option<String>,
+}
+
+fn default_rate_limit() -> Policy {
+	"5/5s".parse().unwrap()
+}
+
+fn default_ingest_rate_limit() -> Policy {
+	"100/s".parse().unwrap()
+}
+
+fn default_newsletter_rate_limit() -> Policy {
+	"3/h".parse().unwrap()
+}
+
+/// The server's state, shared between endpoints.
+pub struct Context {
+	pub db: RwLock<tokio_postgres::Client>,
+	pub tx_db: ConnectionPool,
+	pub uaparser: Renewer<UaParser>,
+	pub geoip: Renewer<GeoIP>,
+	pub crawlers: Renewer<CrawlerList>,
+	pub admin_token: Option<String>,
+	pub scrubber: Scrubber,
+	pub auth_cache: AuthCache,
+	pub nonces: NonceCache,
+	pub property_limiter: PropertyLimiter,
+	pub origin_cache: OriginCache,
+	pub client_ip: Arc<ClientIpResolver>,
+	pub newsletter_confirm_url: Option<Url>,
+	pub mailer: Box<dyn Mailer>,
+}
+
+impl Context {
+	/// Renews downloaded resources.
+	///
+	/// The function returns the names of the resources that could not be renewed.
+	pub async fn renew(&self) -> Vec<&'static str> {
+		let mut failures = vec![];
+		if let Err(error) = self.uaparser.renew().await {
+			warn!(%error, "could not renew UaParser");
+			failures.push("uaparser");
+		}
+		if let Err(error) = self.geoip.renew().await {
+			warn!(%error, "could not renew GeoIP");
+			failures.push("geoip");
+		}
+		if let Err(error) = self.crawlers.renew().await {
+			warn!(%error, "could not renew crawlers list");
+			failures.push("crawlers");
+		}
+		failures
+	}
+}
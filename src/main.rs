@@ -1,55 +1,35 @@
-//! Data collection and authentication service.
+//! The gateway's HTTP server.
 
 #![feature(duration_constructors)]
-#![feature(duration_constructors_lite)]
 
-mod route;
-mod service;
-mod util;
-
-use crate::{
-	service::{geoip::GeoIP, uaparser::UaParser},
-	util::{RenewableInfo, Renewer},
-};
 use axum::{
 	Router,
-	routing::{get, post, put},
+	middleware::from_fn_with_state,
+	routing::{delete, get, patch, post, put},
 };
-use chrono::Utc;
-use gateway_api::log::LogLayer;
-use serde::Deserialize;
+use gateway::{
+	Config, Context, route,
+	service::{
+		mailer::{LogMailer, WebhookMailer},
+		newsletter,
+		property::OriginCache,
+		rate_limit::{PeerLimiter, PropertyLimiter},
+		retention,
+		secret::{self, AuthCache},
+		session,
+		signature::NonceCache,
+		visitor,
+	},
+	util::{ConnectionPool, RenewableInfo, Renewer},
+};
+use gateway_api::{client_ip::ClientIpResolver, log::LogLayer, scrub::Scrubber};
+use reqwest::Url;
 use std::{io, net::SocketAddr, process::exit, sync::Arc, time::Duration};
 use tokio::{select, sync::RwLock, time::interval};
 use tokio_postgres::NoTls;
-use tower_governor::{GovernorLayer, governor::GovernorConfigBuilder};
-use tower_http::{
-	cors,
-	cors::{AllowHeaders, CorsLayer},
-};
+use tower_http::cors::{AllowHeaders, AllowOrigin, CorsLayer};
 use tracing::{error, info, warn};
 
-#[derive(Deserialize)]
-struct Config {
-	/// The port the server listens to.
-	pub port: u32,
-	/// The connection string to the database.
-	pub db: String,
-	/// The URL to fetch uaparser data.
-	pub uaparser_url: String,
-	/// The URL to fetch geoip data.
-	pub geoip_url: String,
-	/// The geoip username (account ID).
-	pub geoip_user: String,
-	/// The geoip password (license key).
-	pub geoip_password: String,
-}
-
-struct Context {
-	db: RwLock<tokio_postgres::Client>,
-	uaparser: Renewer<UaParser>,
-	geoip: Renewer<GeoIP>,
-}
-
 #[tokio::main]
 async fn main() -> io::Result<()> {
 	tracing_subscriber::fmt::init();
@@ -66,8 +46,26 @@ async fn main() -> io::Result<()> {
 			exit(1);
 		});
 	info!("prepare context");
+	let mut client_ip = config
+		.trusted_proxies
+		.into_iter()
+		.fold(ClientIpResolver::default(), ClientIpResolver::trust);
+	if !config.client_ip_headers.is_empty() {
+		client_ip = client_ip.headers(config.client_ip_headers);
+	}
+	let client_ip = Arc::new(client_ip);
+	let newsletter_confirm_url = config.newsletter_confirm_url.map(|url| {
+		Url::parse(&url).unwrap_or_else(|error| {
+			error!(%error, "invalid newsletter confirmation URL");
+			exit(1);
+		})
+	});
+	if newsletter_confirm_url.is_none() {
+		warn!("no newsletter confirmation URL, subscriptions are disabled");
+	}
 	let ctx = Arc::new(Context {
 		db: RwLock::new(client),
+		tx_db: ConnectionPool::new(config.db.clone()),
 		uaparser: Renewer::new(RenewableInfo {
 			url: config.uaparser_url,
 			auth: None,
@@ -88,6 +86,28 @@ async fn main() -> io::Result<()> {
 		})*/
 		.await
 		.expect("GeoIP failure"),
+		crawlers: Renewer::new(RenewableInfo {
+			url: config.crawler_url,
+			auth: None,
+			compressed: false,
+		})
+		.await
+		.expect("crawlers list failure"),
+		admin_token: config.admin_token,
+		scrubber: config
+			.scrub_params
+			.into_iter()
+			.fold(Scrubber::default(), Scrubber::param),
+		auth_cache: AuthCache::default(),
+		nonces: NonceCache::default(),
+		property_limiter: PropertyLimiter::default(),
+		origin_cache: OriginCache::default(),
+		client_ip: client_ip.clone(),
+		newsletter_confirm_url,
+		mailer: match config.mailer_webhook_url {
+			Some(url) => Box::new(WebhookMailer::new(url)),
+			None => Box::new(LogMailer),
+		},
 	});
 	info!("start background tasks");
 	// Setup postgres reconnection task
@@ -119,6 +139,11 @@ async fn main() -> io::Result<()> {
 			break;
 		}
 	});
+	// Requires the connection to be polled by the task above
+	if let Err(error) = secret::migrate_plaintext(&mut *ctx.db.write().await).await {
+		error!(%error, "could not migrate plaintext secrets");
+		exit(1);
+	}
 	// Setup databases renew task
 	let ctx_ = ctx.clone();
 	let renew_task = tokio::spawn(async {
@@ -126,69 +151,155 @@ async fn main() -> io::Result<()> {
 		let ctx = ctx_;
 		loop {
 			interval.tick().await;
-			if let Err(error) = ctx.uaparser.renew().await {
-				warn!(%error, "could not renew UaParser");
-			}
-			if let Err(error) = ctx.geoip.renew().await {
-				warn!(%error, "could not renew GeoIP");
-			}
+			ctx.renew().await;
 		}
 	});
-	// Setup analytics anonymization task
+	// Setup data retention task
 	let ctx_ = ctx.clone();
-	let anonymize_task = tokio::spawn(async {
+	let retention_task = tokio::spawn(async {
 		let mut interval = interval(Duration::from_hours(1));
 		let ctx = ctx_;
 		loop {
 			interval.tick().await;
-			// The end of the date range in which entries are going to be anonymized
-			let end = Utc::now().naive_utc() - Duration::from_days(365);
 			let db = ctx.db.read().await;
-			let res = db.execute(
-                "UPDATE analytics SET peer_addr = NULL, user_agent = NULL WHERE date <= $1 AND (peer_addr IS NOT NULL OR user_agent IS NOT NULL)",
-                &[&end],
-            )
-                .await;
-			if let Err(error) = res {
-				warn!(%error, "could not anonymize analytics");
+			match retention::policies(&db).await {
+				Ok(policies) => match ctx.tx_db.get().await {
+					Ok(mut conn) => {
+						for policy in policies {
+							if let Err(error) = policy.apply(&mut conn).await {
+								warn!(%error, property = %policy.property, "could not apply retention policy");
+							}
+						}
+					}
+					Err(error) => warn!(%error, "could not connect to apply retention policies"),
+				},
+				Err(error) => warn!(%error, "could not retrieve retention policies"),
+			}
+			if let Err(error) = session::purge(&db).await {
+				warn!(%error, "could not purge expired sessions");
+			}
+			if let Err(error) = visitor::purge_salts(&db).await {
+				warn!(%error, "could not purge visitor salts");
+			}
+			if let Err(error) = newsletter::purge_pending(&db).await {
+				warn!(%error, "could not purge pending newsletter subscriptions");
 			}
 		}
 	});
 	// Setup rate limiting
-	let governor_conf = Arc::new(
-		GovernorConfigBuilder::default()
-			.per_second(1)
-			.burst_size(5)
-			.finish()
-			.unwrap(),
-	);
-	let governor_limiter = governor_conf.limiter().clone();
-	let rate_limit_task = tokio::spawn(async move {
-		let mut interval = interval(Duration::from_mins(1));
-		loop {
-			interval.tick().await;
-			governor_limiter.retain_recent();
+	let default_limiter = Arc::new(PeerLimiter::new(config.rate_limit));
+	let ingest_limiter = Arc::new(PeerLimiter::new(config.ingest_rate_limit));
+	let newsletter_limiter = Arc::new(PeerLimiter::new(config.newsletter_rate_limit));
+	let rate_limit_task = tokio::spawn({
+		let default_limiter = default_limiter.clone();
+		let ingest_limiter = ingest_limiter.clone();
+		let newsletter_limiter = newsletter_limiter.clone();
+		async move {
+			let mut interval = interval(Duration::from_mins(1));
+			loop {
+				interval.tick().await;
+				default_limiter.retain_recent();
+				ingest_limiter.retain_recent();
+				newsletter_limiter.retain_recent();
+			}
+		}
+	});
+	// Setup pruning of replay protection nonces
+	let nonce_task = tokio::spawn({
+		let ctx = ctx.clone();
+		async move {
+			let mut interval = interval(Duration::from_mins(1));
+			loop {
+				interval.tick().await;
+				ctx.nonces.prune();
+			}
+		}
+	});
+	// Answer CORS requests according to the origins of properties
+	let cors_ctx = ctx.clone();
+	let allow_origin = AllowOrigin::async_predicate(move |origin, parts| {
+		let ctx = cors_ctx.clone();
+		let path = parts.uri.path().to_owned();
+		async move {
+			let Ok(origin) = origin.to_str() else {
+				return false;
+			};
+			route::allows_origin(&ctx, &path, origin).await
 		}
 	});
 	info!("start http server");
-	let app = Router::new()
-		.route("/health", get(route::health))
+	// Services pushing data in batches get a more generous limit per peer. It still bounds the
+	// cost of authentication attempts, before limits per property apply
+	let ingest = Router::new()
 		.route("/access", put(route::analytics::access))
-		.route("/avatar", get(route::avatar))
+		.route("/event", put(route::event::event))
+		.route_layer(from_fn_with_state(
+			(ctx.clone(), ingest_limiter),
+			route::limit_peer,
+		));
+	let newsletter = Router::new()
 		.route("/newsletter/subscribe", post(route::newsletter::subscribe))
+		.route("/newsletter/confirm", post(route::newsletter::confirm))
 		.route(
 			"/newsletter/unsubscribe",
 			post(route::newsletter::unsubscribe),
 		)
+		.route_layer(from_fn_with_state(
+			(ctx.clone(), newsletter_limiter),
+			route::limit_peer,
+		));
+	let app = Router::new()
+		.route("/health", get(route::health))
+		.route(
+			"/analytics/{property}/timeseries",
+			get(route::analytics::timeseries),
+		)
+		.route(
+			"/analytics/{property}/sessions",
+			get(route::analytics::sessions),
+		)
+		.route("/collect", post(route::collect::collect))
+		.route("/collect.js", get(route::collect::script))
+		.route("/admin/gdpr/export", get(route::admin::export))
+		.route("/admin/gdpr/erase", delete(route::admin::erase))
+		.route("/admin/renew", post(route::admin::renew))
+		.route(
+			"/admin/property",
+			get(route::admin::list_properties).post(route::admin::create_property),
+		)
+		.route(
+			"/admin/property/{property}",
+			patch(route::admin::update_property).delete(route::admin::delete_property),
+		)
+		.route(
+			"/admin/property/{property}/signing-key",
+			post(route::admin::create_signing_key).delete(route::admin::delete_signing_key),
+		)
+		.route(
+			"/admin/property/{property}/secrets",
+			get(route::admin::list_secrets).post(route::admin::create_secret),
+		)
+		.route(
+			"/admin/property/{property}/secrets/{id}",
+			delete(route::admin::revoke_secret),
+		)
+		.route(
+			"/admin/property/{property}/limits",
+			get(route::admin::get_limits).put(route::admin::set_limits),
+		)
+		.route("/avatar", get(route::avatar))
+		.route_layer(from_fn_with_state(
+			(ctx.clone(), default_limiter),
+			route::limit_peer,
+		))
+		.merge(ingest)
+		.merge(newsletter)
 		.layer(
 			CorsLayer::new()
-				.allow_origin(cors::Any)
+				.allow_origin(allow_origin)
 				.allow_headers(AllowHeaders::any()),
 		)
-		.layer(GovernorLayer {
-			config: governor_conf,
-		})
-		.layer(LogLayer)
+		.layer(LogLayer::new(client_ip))
 		.with_state(ctx)
 		.into_make_service_with_connect_info::<SocketAddr>();
 	let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{}", config.port)).await?;
@@ -196,8 +307,9 @@ async fn main() -> io::Result<()> {
 		res = axum::serve(listener, app) => res.expect("HTTP failure"),
 		_ = db_task => panic!("Database task failure"),
 		_ = renew_task => panic!("Resource renew task failure"),
-		_ = anonymize_task => panic!("Anonymization task failure"),
+		_ = retention_task => panic!("Data retention task failure"),
 		_ = rate_limit_task => panic!("Rate limiting task failure"),
+		_ = nonce_task => panic!("Nonce pruning task failure"),
 	}
 	Ok(())
 }
@@ -0,0 +1,701 @@
+//! Administration endpoints.
+
+use crate::{
+	Context,
+	service::{
+		audit,
+		gdpr::{self, Subject},
+		property::{self, PropertyUpdate},
+		rate_limit::{self, Limits},
+		secret,
+	},
+	util::PooledClient,
+};
+use axum::{
+	Json,
+	body::Body,
+	extract::{Path, Query, State},
+	http::{StatusCode, header},
+	response::{IntoResponse, Response},
+};
+use axum_auth::AuthBearer;
+use chrono::TimeDelta;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tokio_postgres::Transaction;
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+/// Checks the given bearer token against the configured admin token.
+///
+/// If no admin token is configured, administration endpoints are disabled.
+///
+/// On failure, the function returns the status to send back to the client.
+pub fn authenticate_admin(ctx: &Context, token: &str) -> Result<(), StatusCode> {
+	let Some(admin_token) = &ctx.admin_token else {
+		return Err(StatusCode::FORBIDDEN);
+	};
+	// Compare digests so that the comparison time does not depend on the token's content
+	if Sha256::digest(token) != Sha256::digest(admin_token) {
+		warn!("admin authentication failure");
+		return Err(StatusCode::UNAUTHORIZED);
+	}
+	Ok(())
+}
+
+/// Returns a dedicated database connection, to run a transaction on.
+///
+/// On failure, the function returns the response to send back to the client.
+async fn connection(ctx: &Context) -> Result<PooledClient<'_>, Response> {
+	ctx.tx_db.get().await.map_err(|error| {
+		error!(%error, "could not connect to database");
+		(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+	})
+}
+
+/// Starts a transaction on `db`.
+///
+/// Changes made by administration endpoints are committed along with their audit log entry, so
+/// that no change is left unrecorded.
+///
+/// On failure, the function returns the response to send back to the client.
+async fn transaction<'c>(db: &'c mut PooledClient<'_>) -> Result<Transaction<'c>, Response> {
+	db.transaction().await.map_err(|error| {
+		error!(%error, "could not start transaction");
+		(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+	})
+}
+
+/// The format of a data export.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+	#[default]
+	Json,
+	Csv,
+}
+
+/// Query of a data subject request.
+#[derive(Deserialize)]
+pub struct SubjectQuery {
+	#[serde(flatten)]
+	subject: Subject,
+	/// The format of the export.
+	#[serde(default)]
+	format: ExportFormat,
+}
+
+/// Endpoint to export all the data linked to a data subject.
+pub async fn export(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Query(query): Query<SubjectQuery>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let criteria = query.subject.criteria();
+	if criteria.is_empty() {
+		return (StatusCode::BAD_REQUEST, "no criteria").into_response();
+	}
+	let db = ctx.db.read().await;
+	let data = match gdpr::find(&db, &query.subject).await {
+		Ok(data) => data,
+		Err(error) => {
+			error!(%error, "could not export data subject's data");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	};
+	// The subject's data is not recorded in the audit log
+	let details = json!({
+		"criteria": criteria,
+		"accesses": data.accesses.len(),
+		"subscriptions": data.subscriptions.len(),
+		"pending_subscriptions": data.pending_subscriptions.len(),
+	});
+	if let Err(error) = audit::record(&*db, "gdpr_export", &details).await {
+		error!(%error, "could not record export in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	info!(%details, "data subject's data exported");
+	match query.format {
+		ExportFormat::Json => Json(data).into_response(),
+		ExportFormat::Csv => (
+			[(header::CONTENT_TYPE, "text/csv; charset=utf-8")],
+			data.to_csv(),
+		)
+			.into_response(),
+	}
+}
+
+/// Endpoint to delete all the data linked to a data subject.
+pub async fn erase(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Query(subject): Query<Subject>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let criteria = subject.criteria();
+	if criteria.is_empty() {
+		return (StatusCode::BAD_REQUEST, "no criteria").into_response();
+	}
+	// The data is deleted only if the erasure is recorded in the audit log
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	let (accesses, subscriptions) = match gdpr::erase(&tx, &subject).await {
+		Ok(counts) => counts,
+		Err(error) => {
+			error!(%error, "could not erase data subject's data");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	};
+	let details = json!({
+		"criteria": criteria,
+		"accesses": accesses,
+		"subscriptions": subscriptions,
+	});
+	if let Err(error) = audit::record(&tx, "gdpr_erase", &details).await {
+		error!(%error, "could not record erasure in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit erasure");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	info!(%details, "data subject's data erased");
+	Json(details).into_response()
+}
+
+/// Endpoint to list the secrets of a property.
+pub async fn list_secrets(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path(property): Path<Uuid>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let db = ctx.db.read().await;
+	match secret::list(&db, &property).await {
+		Ok(secrets) => Json(secrets).into_response(),
+		Err(error) => {
+			error!(%error, "could not list secrets");
+			(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+		}
+	}
+}
+
+/// Endpoint to create a new secret for a property.
+///
+/// Other secrets remain active, so that services can be updated before they are revoked. The
+/// secret is returned only once.
+pub async fn create_secret(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path(property): Path<Uuid>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	let (id, secret) = match secret::create(&tx, &property).await {
+		Ok(Some(secret)) => secret,
+		Ok(None) => return (StatusCode::NOT_FOUND, Body::empty()).into_response(),
+		Err(error) => {
+			error!(%error, "could not create secret");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	};
+	let details = json!({
+		"property": property,
+		"secret": id,
+	});
+	if let Err(error) = audit::record(&tx, "secret_create", &details).await {
+		error!(%error, "could not record secret creation in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit secret creation");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	ctx.auth_cache.invalidate(&property);
+	info!(%property, secret = %id, "secret created");
+	(
+		StatusCode::CREATED,
+		Json(json!({
+			"id": id,
+			"secret": secret,
+		})),
+	)
+		.into_response()
+}
+
+/// Query of a request to revoke a secret.
+#[derive(Deserialize)]
+pub struct RevokeQuery {
+	/// The number of seconds during which the secret remains active.
+	#[serde(default)]
+	grace: u32,
+}
+
+/// Endpoint to revoke a secret of a property.
+pub async fn revoke_secret(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path((property, id)): Path<(Uuid, Uuid)>,
+	Query(query): Query<RevokeQuery>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let grace = TimeDelta::seconds(query.grace as _);
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	match secret::revoke(&tx, &property, &id, grace).await {
+		Ok(true) => {}
+		Ok(false) => return (StatusCode::NOT_FOUND, Body::empty()).into_response(),
+		Err(error) => {
+			error!(%error, "could not revoke secret");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	}
+	let details = json!({
+		"property": property,
+		"secret": id,
+		"grace": query.grace,
+	});
+	if let Err(error) = audit::record(&tx, "secret_revoke", &details).await {
+		error!(%error, "could not record secret revocation in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit secret revocation");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	ctx.auth_cache.invalidate(&property);
+	info!(%property, secret = %id, "secret revoked");
+	(StatusCode::NO_CONTENT, Body::empty()).into_response()
+}
+
+/// Endpoint to create a new signing key for a property, replacing the previous one.
+///
+/// Once a property has a signing key, requests pushing data must be signed with it. The key is
+/// returned only once, as hexadecimal.
+pub async fn create_signing_key(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path(uuid): Path<Uuid>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let key = rand::random::<[u8; 32]>();
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	match property::set_signing_key(&tx, &uuid, Some(&key)).await {
+		Ok(true) => {}
+		Ok(false) => return (StatusCode::NOT_FOUND, Body::empty()).into_response(),
+		Err(error) => {
+			error!(%error, "could not set signing key");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	}
+	let details = json!({
+		"property": uuid,
+	});
+	if let Err(error) = audit::record(&tx, "signing_key_create", &details).await {
+		error!(%error, "could not record signing key creation in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit signing key creation");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	// Cached authentications carry the signing key
+	ctx.auth_cache.invalidate(&uuid);
+	info!(property = %uuid, "signing key created");
+	(
+		StatusCode::CREATED,
+		Json(json!({
+			"signing_key": hex::encode(key),
+		})),
+	)
+		.into_response()
+}
+
+/// Endpoint to remove the signing key of a property, so that requests do not need to be signed
+/// anymore.
+pub async fn delete_signing_key(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path(uuid): Path<Uuid>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	match property::set_signing_key(&tx, &uuid, None).await {
+		Ok(true) => {}
+		Ok(false) => return (StatusCode::NOT_FOUND, Body::empty()).into_response(),
+		Err(error) => {
+			error!(%error, "could not remove signing key");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	}
+	let details = json!({
+		"property": uuid,
+	});
+	if let Err(error) = audit::record(&tx, "signing_key_delete", &details).await {
+		error!(%error, "could not record signing key removal in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit signing key removal");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	// Cached authentications carry the signing key
+	ctx.auth_cache.invalidate(&uuid);
+	info!(property = %uuid, "signing key removed");
+	(StatusCode::NO_CONTENT, Body::empty()).into_response()
+}
+
+/// Endpoint to get the limits of a property, along with its usage for the current month.
+pub async fn get_limits(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path(uuid): Path<Uuid>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let db = ctx.db.read().await;
+	let limits = match property::limits(&db, &uuid).await {
+		Ok(Some(limits)) => limits,
+		Ok(None) => return (StatusCode::NOT_FOUND, Body::empty()).into_response(),
+		Err(error) => {
+			error!(%error, "could not retrieve limits");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	};
+	match rate_limit::usage(&db, &uuid).await {
+		Ok(usage) => Json(json!({
+			"rate_limit": limits.rate_limit,
+			"monthly_quota": limits.monthly_quota,
+			"usage": usage,
+		}))
+		.into_response(),
+		Err(error) => {
+			error!(%error, "could not retrieve usage");
+			(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+		}
+	}
+}
+
+/// Endpoint to set the limits of a property.
+///
+/// Limits that are not specified are removed.
+pub async fn set_limits(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path(uuid): Path<Uuid>,
+	Json(limits): Json<Limits>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	if limits.monthly_quota.is_some_and(|quota| quota < 0) {
+		return (StatusCode::BAD_REQUEST, "invalid quota").into_response();
+	}
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	match property::set_limits(&tx, &uuid, &limits).await {
+		Ok(true) => {}
+		Ok(false) => return (StatusCode::NOT_FOUND, Body::empty()).into_response(),
+		Err(error) => {
+			error!(%error, "could not set limits");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	}
+	let details = json!({
+		"property": uuid,
+		"rate_limit": limits.rate_limit,
+		"monthly_quota": limits.monthly_quota,
+	});
+	if let Err(error) = audit::record(&tx, "limits_update", &details).await {
+		error!(%error, "could not record limits update in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit limits update");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	ctx.property_limiter.invalidate(&uuid);
+	info!(property = %uuid, "limits updated");
+	(StatusCode::NO_CONTENT, Body::empty()).into_response()
+}
+
+/// Payload of a request to create a property.
+#[derive(Deserialize)]
+pub struct CreatePropertyPayload {
+	/// The property's name.
+	name: String,
+	/// The origins allowed to submit data for the property from browsers.
+	#[serde(default)]
+	origins: Vec<String>,
+	/// The hosts accesses may be sent to. If empty, any host is allowed.
+	#[serde(default)]
+	hosts: Vec<String>,
+	/// Tells whether raw IP addresses and user agents are stored.
+	#[serde(default)]
+	store_raw: bool,
+}
+
+/// Endpoint to create a property, along with its first secret.
+///
+/// The secret is returned only once.
+pub async fn create_property(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Json(payload): Json<CreatePropertyPayload>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	if !property::validate_name(&payload.name) {
+		return (StatusCode::BAD_REQUEST, "invalid name").into_response();
+	}
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	let res = property::create(
+		&tx,
+		&payload.name,
+		&payload.origins,
+		&payload.hosts,
+		payload.store_raw,
+	)
+	.await;
+	let uuid = match res {
+		Ok(uuid) => uuid,
+		Err(error) => {
+			error!(%error, "could not create property");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	};
+	let (id, secret) = match secret::create(&tx, &uuid).await {
+		Ok(Some(secret)) => secret,
+		Ok(None) => {
+			error!(property = %uuid, "property vanished before its secret was created");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+		Err(error) => {
+			error!(%error, property = %uuid, "could not create secret");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	};
+	let details = json!({
+		"property": uuid,
+		"secret": id,
+	});
+	if let Err(error) = audit::record(&tx, "property_create", &details).await {
+		error!(%error, "could not record property creation in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit property creation");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	ctx.origin_cache.invalidate(&uuid);
+	info!(property = %uuid, "property created");
+	(
+		StatusCode::CREATED,
+		Json(json!({
+			"uuid": uuid,
+			"secret_id": id,
+			"secret": secret,
+		})),
+	)
+		.into_response()
+}
+
+/// Endpoint to list properties.
+pub async fn list_properties(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let db = ctx.db.read().await;
+	match property::list(&db).await {
+		Ok(properties) => Json(properties).into_response(),
+		Err(error) => {
+			error!(%error, "could not list properties");
+			(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+		}
+	}
+}
+
+/// Endpoint to update a property's settings, such as its name.
+pub async fn update_property(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path(uuid): Path<Uuid>,
+	Json(update): Json<PropertyUpdate>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	if update
+		.name
+		.as_deref()
+		.is_some_and(|name| !property::validate_name(name))
+	{
+		return (StatusCode::BAD_REQUEST, "invalid name").into_response();
+	}
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	match property::update(&tx, &uuid, &update).await {
+		Ok(true) => {}
+		Ok(false) => return (StatusCode::NOT_FOUND, Body::empty()).into_response(),
+		Err(error) => {
+			error!(%error, "could not update property");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	}
+	let details = json!({
+		"property": uuid,
+		"name": update.name,
+		"origins": update.origins,
+		"hosts": update.hosts,
+		"store_raw": update.store_raw,
+	});
+	if let Err(error) = audit::record(&tx, "property_update", &details).await {
+		error!(%error, "could not record property update in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit property update");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	ctx.auth_cache.invalidate(&uuid);
+	ctx.origin_cache.invalidate(&uuid);
+	info!(property = %uuid, "property updated");
+	(StatusCode::NO_CONTENT, Body::empty()).into_response()
+}
+
+/// Endpoint to delete a property, along with all its data.
+pub async fn delete_property(
+	State(ctx): State<Arc<Context>>,
+	AuthBearer(token): AuthBearer,
+	Path(uuid): Path<Uuid>,
+) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let mut db = match connection(&ctx).await {
+		Ok(db) => db,
+		Err(response) => return response,
+	};
+	let tx = match transaction(&mut db).await {
+		Ok(tx) => tx,
+		Err(response) => return response,
+	};
+	match property::delete(&tx, &uuid).await {
+		Ok(true) => {}
+		Ok(false) => return (StatusCode::NOT_FOUND, Body::empty()).into_response(),
+		Err(error) => {
+			error!(%error, "could not delete property");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	}
+	let details = json!({
+		"property": uuid,
+	});
+	if let Err(error) = audit::record(&tx, "property_delete", &details).await {
+		error!(%error, "could not record property deletion in audit log");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	if let Err(error) = tx.commit().await {
+		error!(%error, "could not commit property deletion");
+		return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+	}
+	ctx.auth_cache.invalidate(&uuid);
+	ctx.property_limiter.invalidate(&uuid);
+	ctx.origin_cache.invalidate(&uuid);
+	info!(property = %uuid, "property deleted");
+	(StatusCode::NO_CONTENT, Body::empty()).into_response()
+}
+
+/// Endpoint to renew downloaded resources (UaParser, GeoIP and crawlers list) immediately.
+pub async fn renew(State(ctx): State<Arc<Context>>, AuthBearer(token): AuthBearer) -> Response {
+	if let Err(status) = authenticate_admin(&ctx, &token) {
+		return (status, Body::empty()).into_response();
+	}
+	let failures = ctx.renew().await;
+	if failures.is_empty() {
+		info!("resources renewed");
+		(StatusCode::NO_CONTENT, Body::empty()).into_response()
+	} else {
+		(
+			StatusCode::BAD_GATEWAY,
+			Json(json!({
+				"failures": failures,
+			})),
+		)
+			.into_response()
+	}
+}
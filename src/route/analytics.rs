@@ -1,81 +1,367 @@
 //! Analytics collection.
 
-use crate::{Context, service::property};
+use crate::{
+	Context,
+	route::{authenticate, check_limits, check_signature, release_usage},
+	service::{
+		analytics::{Granularity, sessions as query_sessions, timeseries as query_timeseries},
+		crawler::is_robots_fetch,
+		property,
+		session::{self, Visit},
+		visitor,
+	},
+};
 use axum::{
 	Json,
-	body::Body,
-	extract::State,
-	http::StatusCode,
+	body::{Body, Bytes},
+	extract::{Path, Query, State},
+	http::{HeaderMap, StatusCode},
 	response::{IntoResponse, Response},
 };
 use axum_auth::AuthBasic;
-use gateway_api::analytics::Access;
-use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use gateway_api::{analytics::Access, pool::Record, util::date_format};
+use serde::Deserialize;
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Arc,
+};
 use tracing::{error, warn};
 use uuid::Uuid;
 
-async fn insert_accesses(
+/// Inserts the given accesses for `property`.
+///
+/// Accesses are enriched, classified and assigned a visitor and a session before being written
+/// in a single statement. Personal data is scrubbed from URIs and referers. Unless the property
+/// opted in, raw IP addresses and user agents are not stored.
+///
+/// Accesses sent to a host the property does not allow are dropped, and accesses that have
+/// already been inserted are ignored. The function returns the number of accesses inserted.
+pub async fn insert_accesses(
 	ctx: &Context,
 	property: &Uuid,
-	accesses: Vec<Access>,
-) -> Result<(), tokio_postgres::Error> {
+	mut accesses: Vec<Access>,
+) -> Result<u64, tokio_postgres::Error> {
 	let db = ctx.db.read().await;
-	for access in accesses {
-		let geolocation = access.peer_addr.and_then(|ip| {
-			let geolocation = ctx.geoip.lock().resolve(ip).ok()?;
-			Some(serde_json::to_value(geolocation).unwrap())
+	let settings = property::get(&db, property).await?;
+	if let Some(settings) = &settings {
+		accesses.retain(|access| {
+			let Some(host) = access.host.as_deref() else {
+				return true;
+			};
+			let allowed = settings.allows_host(host);
+			if !allowed {
+				warn!(property = %property, host, "access to a host not allowed");
+			}
+			allowed
 		});
-		let device = access.user_agent.as_ref().map(|ua| {
-			let device = ctx.uaparser.lock().resolve(ua);
-			serde_json::to_value(device).unwrap()
-		});
-		db.execute("INSERT INTO analytics (property, date, peer_addr, user_agent, referer, geolocation, device, method, uri) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) ON CONFLICT DO NOTHING",
+	}
+	if accesses.is_empty() {
+		return Ok(0);
+	}
+	// Resolve data only once per distinct IP address and user agent
+	let mut geolocations = HashMap::new();
+	{
+		let geoip = ctx.geoip.lock();
+		for ip in accesses.iter().filter_map(|access| access.peer_addr) {
+			geolocations.entry(ip).or_insert_with(|| {
+				let geolocation = geoip.resolve(ip).ok()??;
+				Some(serde_json::to_value(geolocation).unwrap())
+			});
+		}
+	}
+	let mut devices = HashMap::new();
+	{
+		let uaparser = ctx.uaparser.lock();
+		let crawlers = ctx.crawlers.lock();
+		for ua in accesses
+			.iter()
+			.filter_map(|access| access.user_agent.as_deref())
+		{
+			devices.entry(ua).or_insert_with(|| {
+				let device = uaparser.resolve(ua);
+				let is_bot = device.is_spider() || crawlers.is_crawler(ua);
+				(serde_json::to_value(device).unwrap(), is_bot)
+			});
+		}
+	}
+	// Build columns
+	let len = accesses.len();
+	let mut id = Vec::with_capacity(len);
+	let mut date = Vec::with_capacity(len);
+	let mut peer_addr = Vec::with_capacity(len);
+	let mut user_agent = Vec::with_capacity(len);
+	let mut referer = Vec::with_capacity(len);
+	let mut geolocation = Vec::with_capacity(len);
+	let mut device = Vec::with_capacity(len);
+	let mut method = Vec::with_capacity(len);
+	let mut uri = Vec::with_capacity(len);
+	let mut host = Vec::with_capacity(len);
+	let mut status = Vec::with_capacity(len);
+	let mut latency = Vec::with_capacity(len);
+	let mut response_size = Vec::with_capacity(len);
+	let mut screen_width = Vec::with_capacity(len);
+	let mut screen_height = Vec::with_capacity(len);
+	let mut opt_out = Vec::with_capacity(len);
+	for access in &accesses {
+		id.push(access.id);
+		date.push(access.date.naive_utc());
+		peer_addr.push(access.peer_addr);
+		user_agent.push(access.user_agent.as_deref());
+		referer.push(
+			access
+				.referer
+				.as_deref()
+				.map(|referer| ctx.scrubber.scrub(referer).into_owned()),
+		);
+		geolocation.push(
+			access
+				.peer_addr
+				.and_then(|ip| geolocations.get(&ip).cloned().flatten()),
+		);
+		device.push(
+			access
+				.user_agent
+				.as_deref()
+				.and_then(|ua| devices.get(ua).map(|(device, _)| device.clone())),
+		);
+		method.push(access.method.as_str());
+		uri.push(ctx.scrubber.scrub(&access.uri).into_owned());
+		host.push(access.host.as_deref());
+		status.push(access.status.map(|status| status as i16));
+		latency.push(
+			access
+				.latency
+				.map(|latency| latency.min(i32::MAX as u64) as i32),
+		);
+		response_size.push(
+			access
+				.response_size
+				.map(|size| size.min(i64::MAX as u64) as i64),
+		);
+		screen_width.push(
+			access
+				.screen_width
+				.map(|width| width.min(i32::MAX as u32) as i32),
+		);
+		screen_height.push(
+			access
+				.screen_height
+				.map(|height| height.min(i32::MAX as u32) as i32),
+		);
+		opt_out.push(access.opt_out);
+	}
+	let store_raw = settings.is_some_and(|property| property.store_raw);
+	let salt = visitor::daily_salt(&db).await?;
+	let visitor_ids: Vec<_> = accesses
+		.iter()
+		.map(|access| {
+			visitor::visitor_id(
+				&salt,
+				property,
+				access.peer_addr,
+				access.user_agent.as_deref(),
+			)
+		})
+		.collect();
+	// Visitors fetching `robots.txt` are crawlers
+	let robots: HashSet<&[u8]> = accesses
+		.iter()
+		.zip(&visitor_ids)
+		.filter(|(access, _)| is_robots_fetch(&access.uri))
+		.filter_map(|(_, visitor_id)| visitor_id.as_deref())
+		.collect();
+	let mut visits: Vec<_> = accesses
+		.iter()
+		.zip(&visitor_ids)
+		.map(|(access, visitor_id)| {
+			let visitor_id = visitor_id.as_deref();
+			// Browsers always send a user agent, unless removed because the client opted out
+			let bot_agent = match access.user_agent.as_deref() {
+				Some(ua) => devices.get(ua).is_some_and(|(_, is_bot)| *is_bot),
+				None => !access.opt_out,
+			};
+			let is_bot = bot_agent
+				|| is_robots_fetch(&access.uri)
+				|| visitor_id.is_some_and(|id| robots.contains(id));
+			Visit {
+				visitor_id,
+				date: access.date.naive_utc(),
+				is_bot,
+				session: None,
+			}
+		})
+		.collect();
+	session::assign(&mut *ctx.tx_db.get().await?, property, &mut visits).await?;
+	let session: Vec<_> = visits.iter().map(|visit| visit.session).collect();
+	let is_bot: Vec<_> = visits.iter().map(|visit| visit.is_bot).collect();
+	// Unless the property opted in, identifying data is not stored
+	if !store_raw {
+		peer_addr.fill(None);
+		user_agent.fill(None);
+	}
+	db.execute(
+		r#"INSERT INTO analytics (property, id, date, peer_addr, user_agent, referer, geolocation, device, method, uri, host, status, latency, response_size, screen_width, screen_height, opt_out, visitor_id, session, is_bot)
+			SELECT $1::UUID, * FROM UNNEST($2::UUID[], $3::TIMESTAMP[], $4::INET[], $5::TEXT[], $6::TEXT[], $7::JSON[], $8::JSON[], $9::TEXT[], $10::TEXT[], $11::TEXT[], $12::SMALLINT[], $13::INTEGER[], $14::BIGINT[], $15::INTEGER[], $16::INTEGER[], $17::BOOLEAN[], $18::BYTEA[], $19::UUID[], $20::BOOLEAN[])
+			ON CONFLICT (property, id) DO NOTHING"#,
 		&[
 			property,
-			&access.date.naive_utc(),
-			&access.peer_addr,
-			&access.user_agent,
-			&access.referer,
+			&id,
+			&date,
+			&peer_addr,
+			&user_agent,
+			&referer,
 			&geolocation,
 			&device,
-			&access.method,
-			&access.uri,
-		]).await?;
-	}
-	Ok(())
+			&method,
+			&uri,
+			&host,
+			&status,
+			&latency,
+			&response_size,
+			&screen_width,
+			&screen_height,
+			&opt_out,
+			&visitor_ids,
+			&session,
+			&is_bot,
+		],
+	)
+	.await
 }
 
 pub async fn access(
 	State(ctx): State<Arc<Context>>,
-	AuthBasic((uuid, secret)): AuthBasic,
-	Json(accesses): Json<Vec<Access>>,
+	AuthBasic(credentials): AuthBasic,
+	headers: HeaderMap,
+	body: Bytes,
 ) -> Response {
-	let (Ok(uuid), Some(Ok(secret))) = (
-		Uuid::parse_str(&uuid),
-		secret.as_deref().map(Uuid::parse_str),
-	) else {
-		warn!("authentication failure");
-		return (StatusCode::UNAUTHORIZED, Body::empty()).into_response();
+	let (uuid, verified) = match authenticate(&ctx, credentials).await {
+		Ok(auth) => auth,
+		Err(response) => return response,
 	};
-	let db = ctx.db.read().await;
-	let res = property::check_auth(&db, &uuid, &secret).await;
-	match res {
-		Ok(true) => {}
-		Ok(false) => {
-			warn!("authentication failure");
-			return (StatusCode::UNAUTHORIZED, Body::empty()).into_response();
-		}
+	let res = check_signature(&ctx, &uuid, &verified, Access::ENDPOINT, &headers, &body);
+	if let Err(status) = res {
+		return (status, Body::empty()).into_response();
+	}
+	let accesses: Vec<Access> = match serde_json::from_slice(&body) {
+		Ok(accesses) => accesses,
 		Err(error) => {
-			error!(%error, "could not check secret");
-			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+			warn!(%error, "invalid accesses payload");
+			return (StatusCode::BAD_REQUEST, Body::empty()).into_response();
 		}
+	};
+	let records = accesses.len();
+	if let Err(response) = check_limits(&ctx, &uuid, records).await {
+		return response;
 	}
 	let res = insert_accesses(&ctx, &uuid, accesses).await;
 	match res {
-		Ok(_) => Response::new(Body::empty()),
+		Ok(inserted) => {
+			release_usage(&ctx, &uuid, records - inserted as usize).await;
+			Response::new(Body::empty())
+		}
 		Err(error) => {
+			release_usage(&ctx, &uuid, records).await;
 			error!(%error, "could not insert accesses");
 			(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
 		}
 	}
 }
+
+/// Checks that the given credentials allow reading the analytics of `property`.
+///
+/// On failure, the function returns the response to send back to the client.
+async fn authorize(
+	ctx: &Context,
+	credentials: (String, Option<String>),
+	property: &Uuid,
+) -> Result<(), Response> {
+	let (uuid, _) = authenticate(ctx, credentials).await?;
+	// A property may only read its own data
+	if uuid != *property {
+		warn!(%uuid, %property, "access to another property's analytics");
+		return Err((StatusCode::FORBIDDEN, Body::empty()).into_response());
+	}
+	Ok(())
+}
+
+/// Query parameters for the timeseries endpoint.
+#[derive(Deserialize)]
+pub struct TimeseriesQuery {
+	/// The beginning of the date range (inclusive).
+	#[serde(with = "date_format")]
+	from: DateTime<Utc>,
+	/// The end of the date range (exclusive).
+	#[serde(with = "date_format")]
+	to: DateTime<Utc>,
+	/// The size of each bucket.
+	granularity: Granularity,
+	/// Tells whether accesses from bots are included.
+	#[serde(default)]
+	bots: bool,
+}
+
+/// Endpoint returning page views and unique visitors of a property over time.
+pub async fn timeseries(
+	State(ctx): State<Arc<Context>>,
+	AuthBasic(credentials): AuthBasic,
+	Path(property): Path<Uuid>,
+	Query(query): Query<TimeseriesQuery>,
+) -> Response {
+	if let Err(response) = authorize(&ctx, credentials, &property).await {
+		return response;
+	}
+	let db = ctx.db.read().await;
+	let res = query_timeseries(
+		&db,
+		&property,
+		&query.from,
+		&query.to,
+		query.granularity,
+		query.bots,
+	)
+	.await;
+	match res {
+		Ok(buckets) => Json(buckets).into_response(),
+		Err(error) => {
+			error!(%error, "could not query analytics timeseries");
+			(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+		}
+	}
+}
+
+/// Query parameters for the sessions endpoint.
+#[derive(Deserialize)]
+pub struct SessionsQuery {
+	/// The beginning of the date range (inclusive).
+	#[serde(with = "date_format")]
+	from: DateTime<Utc>,
+	/// The end of the date range (exclusive).
+	#[serde(with = "date_format")]
+	to: DateTime<Utc>,
+	/// Tells whether accesses from bots are included.
+	#[serde(default)]
+	bots: bool,
+}
+
+/// Endpoint returning visit-level statistics of a property.
+pub async fn sessions(
+	State(ctx): State<Arc<Context>>,
+	AuthBasic(credentials): AuthBasic,
+	Path(property): Path<Uuid>,
+	Query(query): Query<SessionsQuery>,
+) -> Response {
+	if let Err(response) = authorize(&ctx, credentials, &property).await {
+		return response;
+	}
+	let db = ctx.db.read().await;
+	let res = query_sessions(&db, &property, &query.from, &query.to, query.bots).await;
+	match res {
+		Ok(stats) => Json(stats).into_response(),
+		Err(error) => {
+			error!(%error, "could not query analytics sessions");
+			(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+		}
+	}
+}
@@ -0,0 +1,33 @@
+(function () {
+	var script = document.currentScript;
+	var property = script.getAttribute("data-property");
+	var endpoint = new URL("/collect", script.src).href;
+
+	function collect() {
+		var payload = JSON.stringify({
+			property: property,
+			url: location.href,
+			referrer: document.referrer || null,
+			screen_width: screen.width,
+			screen_height: screen.height
+		});
+		// The payload is sent as plain text to avoid a CORS preflight request
+		if (!navigator.sendBeacon || !navigator.sendBeacon(endpoint, payload)) {
+			fetch(endpoint, {
+				method: "POST",
+				body: payload,
+				keepalive: true,
+				mode: "no-cors"
+			});
+		}
+	}
+
+	collect();
+	// Track navigations in single-page applications
+	var pushState = history.pushState;
+	history.pushState = function () {
+		pushState.apply(this, arguments);
+		collect();
+	};
+	window.addEventListener("popstate", collect);
+})();
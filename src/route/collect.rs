@@ -0,0 +1,131 @@
+//! Client-side analytics collection, for pages without a backend.
+
+use crate::{
+	Context,
+	route::{analytics::insert_accesses, check_limits, property_allows_origin, release_usage},
+};
+use axum::{
+	body::{Body, Bytes},
+	extract::{ConnectInfo, State},
+	http::{
+		HeaderMap, StatusCode,
+		header::{CACHE_CONTROL, CONTENT_TYPE, ORIGIN, USER_AGENT},
+	},
+	response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use gateway_api::analytics::{Access, opts_out};
+use reqwest::Url;
+use serde::Deserialize;
+use std::{net::SocketAddr, sync::Arc};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+/// Payload sent by the browser snippet when a page is viewed.
+#[derive(Deserialize)]
+pub struct CollectPayload {
+	/// The property's UUID.
+	property: Uuid,
+	/// The URL of the viewed page.
+	url: String,
+	/// The URL of the previous page, if any.
+	referrer: Option<String>,
+	/// The width of the screen in pixels.
+	screen_width: Option<u32>,
+	/// The height of the screen in pixels.
+	screen_height: Option<u32>,
+}
+
+/// Endpoint serving the browser snippet.
+///
+/// The snippet is included in pages with:
+///
+/// ```html
+/// <script src="https://<gateway>/collect.js" data-property="<property UUID>" defer></script>
+/// ```
+pub async fn script() -> Response {
+	(
+		[
+			(CONTENT_TYPE, "application/javascript"),
+			(CACHE_CONTROL, "max-age=86400"),
+		],
+		include_str!("collect.js"),
+	)
+		.into_response()
+}
+
+/// Beacon endpoint, receiving page views from the browser snippet.
+///
+/// The payload is sent as plain text, to be sent without CORS preflight request. The page must
+/// be on one of the property's allowed origins.
+pub async fn collect(
+	State(ctx): State<Arc<Context>>,
+	ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	body: Bytes,
+) -> Response {
+	let Ok(payload) = serde_json::from_slice::<CollectPayload>(&body) else {
+		return (StatusCode::BAD_REQUEST, "invalid payload").into_response();
+	};
+	// The page must be on the origin sending the request
+	let origin = headers.get(ORIGIN).and_then(|origin| origin.to_str().ok());
+	let url_origin = Url::parse(&payload.url)
+		.ok()
+		.map(|url| url.origin().ascii_serialization());
+	let (Some(origin), Some(url_origin)) = (origin, url_origin) else {
+		return (StatusCode::BAD_REQUEST, "invalid origin").into_response();
+	};
+	if origin != url_origin {
+		warn!(origin, url = %payload.url, "beacon: origin mismatch");
+		return (StatusCode::FORBIDDEN, Body::empty()).into_response();
+	}
+	// The origin must belong to the property
+	let res = property_allows_origin(&ctx, &payload.property, origin).await;
+	match res {
+		Ok(true) => {}
+		Ok(false) => {
+			warn!(origin, property = %payload.property, "beacon: origin not allowed");
+			return (StatusCode::FORBIDDEN, Body::empty()).into_response();
+		}
+		Err(error) => {
+			error!(%error, "could not check origin");
+			return (StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response();
+		}
+	}
+	let access = Access {
+		id: Uuid::new_v4(),
+		date: Utc::now(),
+		peer_addr: ctx.client_ip.resolve(Some(peer_addr.ip()), &headers),
+		user_agent: headers
+			.get(USER_AGENT)
+			.and_then(|ua| ua.to_str().ok())
+			.map(str::to_owned),
+		referer: payload.referrer.filter(|referrer| !referrer.is_empty()),
+		method: "GET".to_owned(),
+		host: Url::parse(&payload.url)
+			.ok()
+			.and_then(|url| url.host_str().map(str::to_owned)),
+		uri: payload.url,
+		status: None,
+		latency: None,
+		response_size: None,
+		screen_width: payload.screen_width,
+		screen_height: payload.screen_height,
+		opt_out: opts_out(&headers),
+	};
+	if let Err(response) = check_limits(&ctx, &payload.property, 1).await {
+		return response;
+	}
+	let res = insert_accesses(&ctx, &payload.property, vec![access]).await;
+	match res {
+		Ok(inserted) => {
+			release_usage(&ctx, &payload.property, 1 - inserted as usize).await;
+			(StatusCode::NO_CONTENT, Body::empty()).into_response()
+		}
+		Err(error) => {
+			release_usage(&ctx, &payload.property, 1).await;
+			error!(%error, "could not insert beacon access");
+			(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+		}
+	}
+}
@@ -0,0 +1,58 @@
+//! Custom events collection.
+
+use crate::{
+	Context,
+	route::{authenticate, check_limits, check_signature, release_usage},
+	service::event::insert_events,
+};
+use axum::{
+	body::{Body, Bytes},
+	extract::State,
+	http::{HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+};
+use axum_auth::AuthBasic;
+use gateway_api::{event::Event, pool::Record};
+use std::sync::Arc;
+use tracing::{error, warn};
+
+/// Endpoint to push custom events.
+pub async fn event(
+	State(ctx): State<Arc<Context>>,
+	AuthBasic(credentials): AuthBasic,
+	headers: HeaderMap,
+	body: Bytes,
+) -> Response {
+	let (uuid, verified) = match authenticate(&ctx, credentials).await {
+		Ok(auth) => auth,
+		Err(response) => return response,
+	};
+	let res = check_signature(&ctx, &uuid, &verified, Event::ENDPOINT, &headers, &body);
+	if let Err(status) = res {
+		return (status, Body::empty()).into_response();
+	}
+	let events: Vec<Event> = match serde_json::from_slice(&body) {
+		Ok(events) => events,
+		Err(error) => {
+			warn!(%error, "invalid events payload");
+			return (StatusCode::BAD_REQUEST, Body::empty()).into_response();
+		}
+	};
+	if let Err(response) = check_limits(&ctx, &uuid, events.len()).await {
+		return response;
+	}
+	let db = ctx.db.read().await;
+	let res = insert_events(&db, &uuid, &events).await;
+	drop(db);
+	match res {
+		Ok(inserted) => {
+			release_usage(&ctx, &uuid, events.len() - inserted as usize).await;
+			Response::new(Body::empty())
+		}
+		Err(error) => {
+			release_usage(&ctx, &uuid, events.len()).await;
+			error!(%error, "could not insert events");
+			(StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response()
+		}
+	}
+}
@@ -1,19 +1,250 @@
 //! The API's endpoints.
 
+pub mod admin;
 pub mod analytics;
+pub mod collect;
+pub mod event;
 pub mod newsletter;
 
-use crate::Context;
+use crate::{
+	Context,
+	service::{
+		property,
+		rate_limit::{self, PeerLimiter, Status},
+		secret::{self, Verified},
+	},
+	util::PgResult,
+};
 use axum::{
 	Json,
 	body::Body,
-	extract::State,
-	http::StatusCode,
+	extract::{ConnectInfo, Request, State},
+	http::{HeaderMap, HeaderValue, StatusCode, header::RETRY_AFTER},
+	middleware::Next,
 	response::{IntoResponse, Response},
 };
 use serde::Serialize;
-use std::sync::Arc;
-use tracing::error;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tracing::{error, warn};
+use uuid::Uuid;
+
+/// Authenticates a property from the given basic auth credentials.
+///
+/// Results are cached, so that most requests do not need to access the database.
+///
+/// On success, the function returns the property's UUID along with the verified secret. On
+/// failure, it returns the response to send back to the client.
+pub async fn authenticate(
+	ctx: &Context,
+	(uuid, secret): (String, Option<String>),
+) -> Result<(Uuid, Verified), Response> {
+	let (Ok(uuid), Some(secret)) = (Uuid::parse_str(&uuid), secret) else {
+		warn!("authentication failure");
+		return Err((StatusCode::UNAUTHORIZED, Body::empty()).into_response());
+	};
+	let verified = match ctx.auth_cache.get(&uuid, &secret) {
+		Some(verified) => verified,
+		None => {
+			let db = ctx.db.read().await;
+			match secret::check(&db, &uuid, &secret).await {
+				Ok(verified) => {
+					ctx.auth_cache.insert(&uuid, &secret, verified.clone());
+					verified
+				}
+				Err(error) => {
+					error!(%error, "could not check secret");
+					return Err((StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response());
+				}
+			}
+		}
+	};
+	let Some(verified) = verified else {
+		warn!("authentication failure");
+		return Err((StatusCode::UNAUTHORIZED, Body::empty()).into_response());
+	};
+	Ok((uuid, verified))
+}
+
+/// Checks the signature of a request pushing data to `endpoint` for `property`, if the property
+/// requires signed requests.
+///
+/// `verified` is the secret the request has been authenticated with, which carries the
+/// property's signing key.
+///
+/// On failure, the function returns the status to send back to the client.
+pub fn check_signature(
+	ctx: &Context,
+	property: &Uuid,
+	verified: &Verified,
+	endpoint: &str,
+	headers: &HeaderMap,
+	body: &[u8],
+) -> Result<(), StatusCode> {
+	let Some(key) = &verified.signing_key else {
+		return Ok(());
+	};
+	ctx.nonces
+		.check(key, property, endpoint, headers, body)
+		.map_err(|reason| {
+			warn!(%property, ?reason, "signature verification failure");
+			StatusCode::UNAUTHORIZED
+		})
+}
+
+/// Returns the given duration in seconds, rounded up.
+fn ceil_secs(duration: Duration) -> u64 {
+	duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Sets the `RateLimit-*` headers describing the given status.
+fn set_rate_limit_headers(headers: &mut HeaderMap, status: &Status) {
+	headers.insert("ratelimit-limit", HeaderValue::from(status.limit));
+	headers.insert("ratelimit-remaining", HeaderValue::from(status.remaining));
+	headers.insert(
+		"ratelimit-reset",
+		HeaderValue::from(ceil_secs(status.reset)),
+	);
+}
+
+/// Returns the response to a request exceeding a rate limit or quota.
+fn too_many_requests(status: &Status) -> Response {
+	let mut response = (StatusCode::TOO_MANY_REQUESTS, Body::empty()).into_response();
+	let headers = response.headers_mut();
+	set_rate_limit_headers(headers, status);
+	headers.insert(RETRY_AFTER, HeaderValue::from(ceil_secs(status.reset)));
+	response
+}
+
+/// Middleware applying a rate limit to each client.
+///
+/// Clients are identified by their IP address, resolved according to the trusted proxies.
+pub async fn limit_peer(
+	State((ctx, limiter)): State<(Arc<Context>, Arc<PeerLimiter>)>,
+	ConnectInfo(peer_addr): ConnectInfo<SocketAddr>,
+	request: Request,
+	next: Next,
+) -> Response {
+	let client = ctx
+		.client_ip
+		.resolve(Some(peer_addr.ip()), request.headers())
+		.unwrap_or(peer_addr.ip());
+	let status = limiter.check(client);
+	if !status.allowed {
+		warn!(%client, path = request.uri().path(), "rate limit exceeded");
+		return too_many_requests(&status);
+	}
+	let mut response = next.run(request).await;
+	set_rate_limit_headers(response.headers_mut(), &status);
+	response
+}
+
+/// Applies the rate limit and the monthly quota of `property` to a request pushing `records`
+/// records.
+///
+/// The records are reserved in the quota. Those that end up not being stored must be released
+/// with [`release_usage`].
+///
+/// On failure, the function returns the response to send back to the client.
+pub async fn check_limits(ctx: &Context, property: &Uuid, records: usize) -> Result<(), Response> {
+	let db = ctx.db.read().await;
+	let limits = match ctx.property_limiter.limits(property) {
+		Some(limits) => limits,
+		None => match property::limits(&db, property).await {
+			Ok(limits) => {
+				let limits = limits.unwrap_or_default();
+				ctx.property_limiter.set_limits(property, limits);
+				limits
+			}
+			Err(error) => {
+				error!(%error, "could not retrieve limits");
+				return Err((StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response());
+			}
+		},
+	};
+	let status = ctx.property_limiter.check(property);
+	if let Some(status) = status.filter(|status| !status.allowed) {
+		warn!(%property, "property rate limit exceeded");
+		return Err(too_many_requests(&status));
+	}
+	let res = rate_limit::reserve_quota(&db, property, limits.monthly_quota, records as i64).await;
+	match res {
+		Ok(Some(status)) if !status.allowed => {
+			warn!(%property, "property monthly quota exceeded");
+			Err(too_many_requests(&status))
+		}
+		Ok(_) => Ok(()),
+		Err(error) => {
+			error!(%error, "could not check quota");
+			Err((StatusCode::INTERNAL_SERVER_ERROR, Body::empty()).into_response())
+		}
+	}
+}
+
+/// Releases `records` records reserved by [`check_limits`] for `property`, which have not been
+/// stored (because they were rejected, duplicates, or the insertion failed).
+///
+/// A failure is only logged: the records are then counted against the quota.
+pub async fn release_usage(ctx: &Context, property: &Uuid, records: usize) {
+	if records == 0 {
+		return;
+	}
+	let db = ctx.db.read().await;
+	if let Err(error) = rate_limit::release_usage(&db, property, records as i64).await {
+		error!(%error, %property, "could not release usage");
+	}
+}
+
+/// Tells whether `origin` is allowed for `property`.
+///
+/// Allowed origins are cached, since they are checked on every cross-origin request.
+pub async fn property_allows_origin(
+	ctx: &Context,
+	property: &Uuid,
+	origin: &str,
+) -> PgResult<bool> {
+	if let Some(allowed) = ctx.origin_cache.allows(property, origin) {
+		return Ok(allowed);
+	}
+	let db = ctx.db.read().await;
+	let origins = property::origins(&db, property).await?;
+	let allowed = origins.iter().any(|o| o == origin);
+	ctx.origin_cache.set(property, origins);
+	Ok(allowed)
+}
+
+/// Tells whether `origin` is allowed for any property.
+///
+/// Allowed origins are cached, since they are checked on every cross-origin request.
+async fn any_allows_origin(ctx: &Context, origin: &str) -> PgResult<bool> {
+	if let Some(allowed) = ctx.origin_cache.any_allows(origin) {
+		return Ok(allowed);
+	}
+	let db = ctx.db.read().await;
+	let origins = property::all_origins(&db).await?;
+	let allowed = origins.contains(origin);
+	ctx.origin_cache.set_all(origins);
+	Ok(allowed)
+}
+
+/// Tells whether cross-origin requests from `origin` to `path` are allowed.
+///
+/// Requests to a property's analytics are allowed only from the property's origins. Other
+/// requests are allowed from the origins of any property.
+pub async fn allows_origin(ctx: &Context, path: &str, origin: &str) -> bool {
+	let property = path
+		.strip_prefix("/analytics/")
+		.and_then(|rest| rest.split('/').next())
+		.map(Uuid::parse_str);
+	let res = match property {
+		Some(Ok(uuid)) => property_allows_origin(ctx, &uuid, origin).await,
+		Some(Err(_)) => Ok(false),
+		None => any_allows_origin(ctx, origin).await,
+	};
+	res.unwrap_or_else(|error| {
+		error!(%error, "could not check origin");
+		false
+	})
+}
 
 /// Json representing the service's health.
 #[derive(Serialize)]
@@ -2,7 +2,10 @@
 
 use crate::{
 	Context,
-	service::newsletter::{insert_subscriber, unsubscribe_from_token},
+	service::{
+		mailer::Email,
+		newsletter::{self, CONFIRM_TTL, unsubscribe_from_token},
+	},
 	util::validate_email,
 };
 use axum::{
@@ -14,7 +17,8 @@ use axum::{
 };
 use serde::Deserialize;
 use std::sync::Arc;
-use tracing::error;
+use tracing::{error, warn};
+use uuid::Uuid;
 
 /// Payload of request to register a newsletter subscriber.
 #[derive(Deserialize)]
@@ -23,6 +27,13 @@ pub struct SubscribePayload {
 	email: String,
 }
 
+/// Payload of request to confirm a newsletter subscription.
+#[derive(Deserialize)]
+pub struct ConfirmPayload {
+	/// The confirmation token.
+	token: Uuid,
+}
+
 /// Payload of request to unregister a newsletter subscriber.
 #[derive(Deserialize)]
 pub struct UnsubscribePayload {
@@ -31,6 +42,12 @@ pub struct UnsubscribePayload {
 }
 
 /// Endpoint to subscribe to a newsletter.
+///
+/// The subscription is pending until confirmed with the token sent to the address. The response
+/// does not tell whether the address is already subscribed.
+///
+/// If no confirmation page is configured, subscriptions are refused, since they could not be
+/// confirmed.
 pub async fn subscribe(
 	State(ctx): State<Arc<Context>>,
 	Json(payload): Json<SubscribePayload>,
@@ -38,12 +55,59 @@ pub async fn subscribe(
 	if !validate_email(&payload.email) {
 		return (StatusCode::BAD_REQUEST, "invalid email address").into_response();
 	}
+	let Some(confirm_url) = &ctx.newsletter_confirm_url else {
+		warn!("newsletter subscription refused: no confirmation URL configured");
+		return (StatusCode::SERVICE_UNAVAILABLE, "subscriptions disabled").into_response();
+	};
 	let db = ctx.db.read().await;
-	let res = insert_subscriber(&db, &payload.email).await;
-	match res {
+	let res = newsletter::insert_pending(&db, &payload.email).await;
+	drop(db);
+	let token = match res {
+		Ok(Some(token)) => token,
+		Ok(None) => return Response::new(Body::empty()),
+		Err(error) => {
+			error!(%error, "could not add pending newsletter subscription");
+			return (StatusCode::INTERNAL_SERVER_ERROR, "internal server error").into_response();
+		}
+	};
+	let mut link = confirm_url.clone();
+	link.query_pairs_mut()
+		.append_pair("token", &token.to_string());
+	let body = format!(
+		"Please confirm your subscription to the newsletter by opening the following link:\n\n\
+		{link}\n\n\
+		The link expires in {} hours. If you did not subscribe, you can ignore this email.\n",
+		CONFIRM_TTL.num_hours()
+	);
+	let email = Email {
+		to: &payload.email,
+		subject: "Confirm your newsletter subscription",
+		body: &body,
+	};
+	match ctx.mailer.send(&email).await {
 		Ok(_) => Response::new(Body::empty()),
 		Err(error) => {
-			error!(%error, "could not add newsletter subscriber");
+			error!(%error, "could not send newsletter confirmation email");
+			(StatusCode::INTERNAL_SERVER_ERROR, "internal server error").into_response()
+		}
+	}
+}
+
+/// Endpoint to confirm a newsletter subscription.
+pub async fn confirm(
+	State(ctx): State<Arc<Context>>,
+	Json(payload): Json<ConfirmPayload>,
+) -> Response {
+	let db = ctx.db.read().await;
+	let res = newsletter::confirm(&db, &payload.token).await;
+	match res {
+		Ok(true) => Response::new(Body::empty()),
+		Ok(false) => {
+			warn!("invalid or expired newsletter confirmation token");
+			(StatusCode::NOT_FOUND, "invalid or expired token").into_response()
+		}
+		Err(error) => {
+			error!(%error, "could not confirm newsletter subscription");
 			(StatusCode::INTERNAL_SERVER_ERROR, "internal server error").into_response()
 		}
 	}
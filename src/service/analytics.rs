@@ -0,0 +1,240 @@
+//! Analytics statistics.
+
+use crate::util::{PgResult, write_csv_line};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use gateway_api::util::date_format;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use tokio_postgres::{Row, types::ToSql};
+use uuid::Uuid;
+
+/// The size of the buckets of a timeseries.
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+	Hour,
+	Day,
+	Month,
+}
+
+impl Granularity {
+	/// Returns the name of the field to truncate dates to, as expected by `date_trunc`.
+	fn field(self) -> &'static str {
+		match self {
+			Self::Hour => "hour",
+			Self::Day => "day",
+			Self::Month => "month",
+		}
+	}
+}
+
+/// A bucket of a timeseries.
+#[derive(Serialize)]
+pub struct TimeseriesBucket {
+	/// The beginning of the bucket.
+	#[serde(with = "date_format")]
+	pub date: DateTime<Utc>,
+	/// The number of page views.
+	pub views: i64,
+	/// The number of unique visitors per day, summed over the bucket's days.
+	///
+	/// Visitor identifiers change every day, so a visitor coming back on different days of the
+	/// bucket is counted once per day.
+	pub visitors: i64,
+	/// The number of page views of clients asking not to be tracked.
+	pub opted_out: i64,
+}
+
+/// Returns page views and unique visitors for `property` in the range `[from, to[`, grouped by
+/// buckets of the given `granularity`.
+///
+/// Buckets without any access are not returned. Accesses from bots are counted only if `bots` is
+/// `true`.
+///
+/// Visitor identifiers change every day, so unique visitors are counted per day: with a daily or
+/// monthly granularity, a visitor coming back on different days is counted once per day.
+///
+/// Days whose accesses have been rolled up by the retention policy are included, except with an
+/// hourly granularity.
+pub async fn timeseries(
+	db: &tokio_postgres::Client,
+	property: &Uuid,
+	from: &DateTime<Utc>,
+	to: &DateTime<Utc>,
+	granularity: Granularity,
+	bots: bool,
+) -> PgResult<Vec<TimeseriesBucket>> {
+	let rows = db
+		.query(
+			r#"WITH raw AS (
+					SELECT date_trunc($4, date) AS bucket, COUNT(*) AS views, COUNT(DISTINCT visitor_id) AS visitors, COUNT(*) FILTER (WHERE opt_out) AS opted_out
+					FROM analytics
+					WHERE property = $1 AND date >= $2 AND date < $3 AND ($5 OR NOT is_bot)
+					GROUP BY bucket
+				), daily AS (
+					SELECT date_trunc($4, day::TIMESTAMP) AS bucket, SUM(views) AS views, SUM(visitors) AS visitors, SUM(opted_out) AS opted_out
+					FROM analytics_daily
+					WHERE property = $1 AND day::TIMESTAMP >= $2 AND day::TIMESTAMP < $3 AND ($5 OR NOT is_bot) AND $4 <> 'hour'
+					GROUP BY bucket
+				)
+				SELECT bucket, SUM(views)::BIGINT, SUM(visitors)::BIGINT, SUM(opted_out)::BIGINT
+				FROM (SELECT * FROM raw UNION ALL SELECT * FROM daily) AS buckets
+				GROUP BY bucket ORDER BY bucket"#,
+			&[
+				property,
+				&from.naive_utc(),
+				&to.naive_utc(),
+				&granularity.field(),
+				&bots,
+			],
+		)
+		.await?;
+	let buckets = rows
+		.into_iter()
+		.map(|row| TimeseriesBucket {
+			date: row.get::<_, NaiveDateTime>(0).and_utc(),
+			views: row.get(1),
+			visitors: row.get(2),
+			opted_out: row.get(3),
+		})
+		.collect();
+	Ok(buckets)
+}
+
+/// The number of sessions starting or ending on a page.
+#[derive(Serialize)]
+pub struct PageCount {
+	/// The page's URI.
+	pub uri: String,
+	/// The number of sessions.
+	pub sessions: i64,
+}
+
+/// Visit-level statistics.
+#[derive(Serialize)]
+pub struct SessionStats {
+	/// The number of sessions.
+	pub sessions: i64,
+	/// The average number of page views per session.
+	pub pages_per_session: f64,
+	/// The fraction of sessions with a single page view.
+	pub bounce_rate: f64,
+	/// The pages on which sessions start the most.
+	pub entry_pages: Vec<PageCount>,
+	/// The pages on which sessions end the most.
+	pub exit_pages: Vec<PageCount>,
+}
+
+/// The maximum number of entry and exit pages returned in [`SessionStats`].
+const TOP_PAGES: i64 = 10;
+
+/// Returns visit-level statistics for `property`, for sessions with accesses in the range
+/// `[from, to[`.
+///
+/// Sessions are tied to visitor identifiers, which change every day, so a visit spanning midnight
+/// (UTC) counts as two sessions.
+///
+/// Sessions of bots are counted only if `bots` is `true`.
+pub async fn sessions(
+	db: &tokio_postgres::Client,
+	property: &Uuid,
+	from: &DateTime<Utc>,
+	to: &DateTime<Utc>,
+	bots: bool,
+) -> PgResult<SessionStats> {
+	const SESSIONS: &str = r#"WITH s AS (
+			SELECT COUNT(*) AS views,
+				(array_agg(uri ORDER BY date ASC))[1] AS entry_page,
+				(array_agg(uri ORDER BY date DESC))[1] AS exit_page
+			FROM analytics
+			WHERE property = $1 AND date >= $2 AND date < $3 AND session IS NOT NULL AND ($4 OR NOT is_bot)
+			GROUP BY session
+		)"#;
+	let params: [&(dyn ToSql + Sync); 4] = [property, &from.naive_utc(), &to.naive_utc(), &bots];
+	let row = db
+		.query_one(
+			&format!(
+				"{SESSIONS} SELECT COUNT(*), COALESCE(SUM(views), 0)::BIGINT, COUNT(*) FILTER (WHERE views = 1) FROM s"
+			),
+			&params,
+		)
+		.await?;
+	let sessions: i64 = row.get(0);
+	let views: i64 = row.get(1);
+	let bounces: i64 = row.get(2);
+	let top_pages = |column: &str| {
+		format!(
+			"{SESSIONS} SELECT {column}, COUNT(*) AS n FROM s GROUP BY {column} ORDER BY n DESC LIMIT {TOP_PAGES}"
+		)
+	};
+	let to_counts = |rows: Vec<Row>| -> Vec<PageCount> {
+		rows.into_iter()
+			.map(|row| PageCount {
+				uri: row.get(0),
+				sessions: row.get(1),
+			})
+			.collect()
+	};
+	let entry_pages = to_counts(db.query(&top_pages("entry_page"), &params).await?);
+	let exit_pages = to_counts(db.query(&top_pages("exit_page"), &params).await?);
+	let ratio = |n: i64| {
+		if sessions > 0 {
+			n as f64 / sessions as f64
+		} else {
+			0.
+		}
+	};
+	Ok(SessionStats {
+		sessions,
+		pages_per_session: ratio(views),
+		bounce_rate: ratio(bounces),
+		entry_pages,
+		exit_pages,
+	})
+}
+
+/// Returns the accesses of `property` in the range `[from, to[` as CSV, from oldest to newest.
+pub async fn export_csv(
+	db: &tokio_postgres::Client,
+	property: &Uuid,
+	from: &DateTime<Utc>,
+	to: &DateTime<Utc>,
+) -> PgResult<String> {
+	let rows = db
+		.query(
+			r#"SELECT id, date, method, uri, referer, status, latency, response_size, screen_width, screen_height, session, is_bot, opt_out, peer_addr, user_agent
+				FROM analytics
+				WHERE property = $1 AND date >= $2 AND date < $3
+				ORDER BY date"#,
+			&[property, &from.naive_utc(), &to.naive_utc()],
+		)
+		.await?;
+	let mut csv = String::from(
+		"id,date,method,uri,referer,status,latency,response_size,screen_width,screen_height,session,is_bot,opt_out,peer_addr,user_agent\r\n",
+	);
+	let opt = |value: Option<String>| value.unwrap_or_default();
+	for row in rows {
+		let date = row.get::<_, NaiveDateTime>(1).and_utc();
+		let fields = [
+			row.get::<_, Uuid>(0).to_string(),
+			date.format(date_format::FORMAT).to_string(),
+			row.get(2),
+			row.get(3),
+			opt(row.get(4)),
+			opt(row.get::<_, Option<i16>>(5).map(|n| n.to_string())),
+			opt(row.get::<_, Option<i32>>(6).map(|n| n.to_string())),
+			opt(row.get::<_, Option<i64>>(7).map(|n| n.to_string())),
+			opt(row.get::<_, Option<i32>>(8).map(|n| n.to_string())),
+			opt(row.get::<_, Option<i32>>(9).map(|n| n.to_string())),
+			opt(row.get::<_, Option<Uuid>>(10).map(|id| id.to_string())),
+			row.get::<_, bool>(11).to_string(),
+			row.get::<_, bool>(12).to_string(),
+			opt(row
+				.get::<_, Option<IpAddr>>(13)
+				.map(|addr| addr.to_string())),
+			opt(row.get(14)),
+		];
+		write_csv_line(&mut csv, &fields);
+	}
+	Ok(csv)
+}
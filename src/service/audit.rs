@@ -0,0 +1,21 @@
+//! Audit log of administrative actions.
+
+use crate::util::PgResult;
+use chrono::Utc;
+use serde_json::Value;
+use tokio_postgres::GenericClient;
+use uuid::Uuid;
+
+/// Records an administrative action in the audit log.
+///
+/// `details` describes the action. It must not contain personal data.
+pub async fn record(db: &impl GenericClient, action: &str, details: &Value) -> PgResult<()> {
+	let id = Uuid::new_v4();
+	let now = Utc::now().naive_utc();
+	db.execute(
+		"INSERT INTO audit_log (id, date, action, details) VALUES ($1, $2, $3, $4)",
+		&[&id, &now, &action, details],
+	)
+	.await?;
+	Ok(())
+}
@@ -0,0 +1,40 @@
+use crate::util::Renewable;
+use anyhow::Result;
+use regex::{Regex, RegexSet};
+use serde::Deserialize;
+
+/// An entry of the crawlers list.
+#[derive(Deserialize)]
+struct CrawlerEntry {
+	/// Regular expression matching the crawler's user agent.
+	pattern: String,
+}
+
+/// List of known crawlers, matching their user agents.
+pub struct CrawlerList(RegexSet);
+
+impl Renewable for CrawlerList {
+	fn new(data: Vec<u8>) -> Result<Self> {
+		let entries: Vec<CrawlerEntry> = serde_json::from_slice(&data)?;
+		// Ignore patterns that are not supported, instead of rejecting the whole list
+		let patterns = entries
+			.into_iter()
+			.map(|entry| entry.pattern)
+			.filter(|pattern| Regex::new(pattern).is_ok());
+		Ok(Self(RegexSet::new(patterns)?))
+	}
+}
+
+impl CrawlerList {
+	/// Tells whether the given user agent belongs to a known crawler.
+	pub fn is_crawler(&self, user_agent: &str) -> bool {
+		self.0.is_match(user_agent)
+	}
+}
+
+/// Tells whether the given URI is the one of a `robots.txt` file, which is fetched by crawlers
+/// only.
+pub fn is_robots_fetch(uri: &str) -> bool {
+	let path = uri.split(['?', '#']).next().unwrap_or_default();
+	path.ends_with("/robots.txt")
+}
@@ -0,0 +1,30 @@
+//! Custom events logic.
+
+use crate::util::PgResult;
+use gateway_api::event::Event;
+use uuid::Uuid;
+
+/// Inserts the given events for `property`, in a single statement.
+///
+/// Events that have already been inserted are ignored. The function returns the number of
+/// events inserted.
+pub async fn insert_events(
+	db: &tokio_postgres::Client,
+	property: &Uuid,
+	events: &[Event],
+) -> PgResult<u64> {
+	if events.is_empty() {
+		return Ok(0);
+	}
+	let id: Vec<_> = events.iter().map(|event| event.id).collect();
+	let date: Vec<_> = events.iter().map(|event| event.date.naive_utc()).collect();
+	let name: Vec<_> = events.iter().map(|event| event.name.as_str()).collect();
+	let properties: Vec<_> = events.iter().map(|event| &event.properties).collect();
+	db.execute(
+		r#"INSERT INTO event (property, id, date, name, properties)
+			SELECT $1::UUID, * FROM UNNEST($2::UUID[], $3::TIMESTAMP[], $4::TEXT[], $5::JSONB[])
+			ON CONFLICT (property, id) DO NOTHING"#,
+		&[property, &id, &date, &name, &properties],
+	)
+	.await
+}
@@ -0,0 +1,270 @@
+//! Data subject requests (GDPR).
+//!
+//! A data subject is identified by any combination of an IP address, a user agent and an email.
+//! Accesses must match all the given criteria among the IP address and user agent, since a user
+//! agent alone is shared by many people.
+//! Accesses stored without raw data (see the property's `store_raw` setting) cannot be linked to
+//! a subject, and are therefore never returned.
+
+use crate::util::{PgResult, write_csv_line};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use gateway_api::util::{date_format, option_date_format};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::net::IpAddr;
+use tokio_postgres::GenericClient;
+use uuid::Uuid;
+
+/// The criteria identifying a data subject.
+#[derive(Deserialize)]
+pub struct Subject {
+	/// The subject's IP address.
+	pub ip: Option<IpAddr>,
+	/// The subject's user agent.
+	pub user_agent: Option<String>,
+	/// The subject's email.
+	pub email: Option<String>,
+}
+
+impl Subject {
+	/// Returns the names of the criteria that are set.
+	///
+	/// This allows to describe a request without disclosing the subject's data.
+	pub fn criteria(&self) -> Vec<&'static str> {
+		[
+			("ip", self.ip.is_some()),
+			("user_agent", self.user_agent.is_some()),
+			("email", self.email.is_some()),
+		]
+		.into_iter()
+		.filter(|(_, set)| *set)
+		.map(|(name, _)| name)
+		.collect()
+	}
+
+	/// Tells whether the subject's accesses can be looked up, that is if an IP address or a user
+	/// agent is given.
+	fn has_access_criteria(&self) -> bool {
+		self.ip.is_some() || self.user_agent.is_some()
+	}
+}
+
+/// An access linked to a data subject.
+#[derive(Serialize)]
+pub struct SubjectAccess {
+	pub property: Uuid,
+	pub id: Uuid,
+	#[serde(with = "date_format")]
+	pub date: DateTime<Utc>,
+	pub peer_addr: Option<IpAddr>,
+	pub user_agent: Option<String>,
+	pub referer: Option<String>,
+	pub geolocation: Option<Value>,
+	pub device: Option<Value>,
+	pub method: String,
+	pub uri: String,
+}
+
+/// A newsletter subscription linked to a data subject.
+#[derive(Serialize)]
+pub struct SubjectSubscription {
+	pub email: String,
+	#[serde(with = "date_format")]
+	pub subscribe_date: DateTime<Utc>,
+	#[serde(with = "option_date_format")]
+	pub unsubscribe_date: Option<DateTime<Utc>>,
+}
+
+/// A newsletter subscription awaiting confirmation, linked to a data subject.
+#[derive(Serialize)]
+pub struct SubjectPendingSubscription {
+	pub email: String,
+	#[serde(with = "date_format")]
+	pub request_date: DateTime<Utc>,
+}
+
+/// All the data linked to a data subject.
+#[derive(Serialize)]
+pub struct SubjectData {
+	pub accesses: Vec<SubjectAccess>,
+	pub subscriptions: Vec<SubjectSubscription>,
+	pub pending_subscriptions: Vec<SubjectPendingSubscription>,
+}
+
+impl SubjectData {
+	/// Returns the data as CSV, with one line per access or subscription.
+	///
+	/// The `table` column tells where each line comes from. Columns that do not apply to a line
+	/// are left empty.
+	pub fn to_csv(&self) -> String {
+		let mut csv = String::from(
+			"table,property,id,date,peer_addr,user_agent,referer,geolocation,device,method,uri,email,unsubscribe_date\r\n",
+		);
+		let date = |date: &DateTime<Utc>| date.format(date_format::FORMAT).to_string();
+		for access in &self.accesses {
+			let fields = [
+				"analytics".to_owned(),
+				access.property.to_string(),
+				access.id.to_string(),
+				date(&access.date),
+				access
+					.peer_addr
+					.map(|addr| addr.to_string())
+					.unwrap_or_default(),
+				access.user_agent.clone().unwrap_or_default(),
+				access.referer.clone().unwrap_or_default(),
+				access
+					.geolocation
+					.as_ref()
+					.map(Value::to_string)
+					.unwrap_or_default(),
+				access
+					.device
+					.as_ref()
+					.map(Value::to_string)
+					.unwrap_or_default(),
+				access.method.clone(),
+				access.uri.clone(),
+				String::new(),
+				String::new(),
+			];
+			write_csv_line(&mut csv, &fields);
+		}
+		for subscription in &self.subscriptions {
+			let fields = [
+				"newsletter_subscriber".to_owned(),
+				String::new(),
+				String::new(),
+				date(&subscription.subscribe_date),
+				String::new(),
+				String::new(),
+				String::new(),
+				String::new(),
+				String::new(),
+				String::new(),
+				String::new(),
+				subscription.email.clone(),
+				subscription
+					.unsubscribe_date
+					.as_ref()
+					.map(date)
+					.unwrap_or_default(),
+			];
+			write_csv_line(&mut csv, &fields);
+		}
+		for subscription in &self.pending_subscriptions {
+			let fields = [
+				"newsletter_pending".to_owned(),
+				String::new(),
+				String::new(),
+				date(&subscription.request_date),
+				String::new(),
+				String::new(),
+				String::new(),
+				String::new(),
+				String::new(),
+				String::new(),
+				String::new(),
+				subscription.email.clone(),
+				String::new(),
+			];
+			write_csv_line(&mut csv, &fields);
+		}
+		csv
+	}
+}
+
+/// Returns all the data linked to `subject`.
+pub async fn find(db: &tokio_postgres::Client, subject: &Subject) -> PgResult<SubjectData> {
+	let rows = if subject.has_access_criteria() {
+		db.query(
+			r#"SELECT property, id, date, peer_addr, user_agent, referer, geolocation, device, method, uri
+				FROM analytics
+				WHERE ($1::INET IS NULL OR peer_addr = $1) AND ($2::TEXT IS NULL OR user_agent = $2)
+				ORDER BY date"#,
+			&[&subject.ip, &subject.user_agent],
+		)
+		.await?
+	} else {
+		vec![]
+	};
+	let accesses = rows
+		.into_iter()
+		.map(|row| SubjectAccess {
+			property: row.get(0),
+			id: row.get(1),
+			date: row.get::<_, NaiveDateTime>(2).and_utc(),
+			peer_addr: row.get(3),
+			user_agent: row.get(4),
+			referer: row.get(5),
+			geolocation: row.get(6),
+			device: row.get(7),
+			method: row.get(8),
+			uri: row.get(9),
+		})
+		.collect();
+	let rows = db
+		.query(
+			"SELECT email, subscribe_date, unsubscribe_date FROM newsletter_subscriber WHERE email = $1",
+			&[&subject.email],
+		)
+		.await?;
+	let subscriptions = rows
+		.into_iter()
+		.map(|row| SubjectSubscription {
+			email: row.get(0),
+			subscribe_date: row.get::<_, NaiveDateTime>(1).and_utc(),
+			unsubscribe_date: row
+				.get::<_, Option<NaiveDateTime>>(2)
+				.map(|date| date.and_utc()),
+		})
+		.collect();
+	let rows = db
+		.query(
+			"SELECT email, request_date FROM newsletter_pending WHERE email = $1",
+			&[&subject.email],
+		)
+		.await?;
+	let pending_subscriptions = rows
+		.into_iter()
+		.map(|row| SubjectPendingSubscription {
+			email: row.get(0),
+			request_date: row.get::<_, NaiveDateTime>(1).and_utc(),
+		})
+		.collect();
+	Ok(SubjectData {
+		accesses,
+		subscriptions,
+		pending_subscriptions,
+	})
+}
+
+/// Deletes all the data linked to `subject`.
+///
+/// The function returns the number of deleted accesses and subscriptions, including pending ones.
+/// It should be called in a transaction, along with the recording of the erasure in the audit log.
+pub async fn erase(db: &impl GenericClient, subject: &Subject) -> PgResult<(u64, u64)> {
+	let accesses = if subject.has_access_criteria() {
+		db.execute(
+			r#"DELETE FROM analytics
+				WHERE ($1::INET IS NULL OR peer_addr = $1) AND ($2::TEXT IS NULL OR user_agent = $2)"#,
+			&[&subject.ip, &subject.user_agent],
+		)
+		.await?
+	} else {
+		0
+	};
+	let subscriptions = db
+		.execute(
+			"DELETE FROM newsletter_subscriber WHERE email = $1",
+			&[&subject.email],
+		)
+		.await?;
+	let pending = db
+		.execute(
+			"DELETE FROM newsletter_pending WHERE email = $1",
+			&[&subject.email],
+		)
+		.await?;
+	Ok((accesses, subscriptions + pending))
+}
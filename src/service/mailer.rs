@@ -0,0 +1,87 @@
+//! Dispatch of emails.
+
+use anyhow::{Result, bail};
+use reqwest::header::CONTENT_TYPE;
+use serde_json::json;
+use std::{future::Future, pin::Pin};
+use tracing::info;
+
+/// An email to send.
+pub struct Email<'e> {
+	/// The recipient's address.
+	pub to: &'e str,
+	/// The email's subject.
+	pub subject: &'e str,
+	/// The email's body, as plain text.
+	pub body: &'e str,
+}
+
+/// The future returned by [`Mailer::send`].
+pub type SendFuture<'m> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'm>>;
+
+/// A way to dispatch emails.
+pub trait Mailer: Send + Sync {
+	/// Sends the given email.
+	fn send<'m>(&'m self, email: &'m Email<'m>) -> SendFuture<'m>;
+}
+
+/// Mailer writing emails to logs instead of sending them, for development.
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+	fn send<'m>(&'m self, email: &'m Email<'m>) -> SendFuture<'m> {
+		Box::pin(async move {
+			info!(
+				to = email.to,
+				subject = email.subject,
+				body = email.body,
+				"email"
+			);
+			Ok(())
+		})
+	}
+}
+
+/// Mailer posting emails as JSON to a webhook, which is in charge of delivering them.
+///
+/// The payload is an object with the `to`, `subject` and `body` fields.
+pub struct WebhookMailer {
+	/// The webhook's URL.
+	url: String,
+	/// The HTTP client.
+	client: reqwest::Client,
+}
+
+impl WebhookMailer {
+	/// Creates a mailer posting to the given URL.
+	pub fn new(url: String) -> Self {
+		Self {
+			url,
+			client: reqwest::Client::new(),
+		}
+	}
+}
+
+impl Mailer for WebhookMailer {
+	fn send<'m>(&'m self, email: &'m Email<'m>) -> SendFuture<'m> {
+		Box::pin(async move {
+			let payload = json!({
+				"to": email.to,
+				"subject": email.subject,
+				"body": email.body,
+			});
+			let response = self
+				.client
+				.post(&self.url)
+				.header(CONTENT_TYPE, "application/json")
+				.body(payload.to_string())
+				.send()
+				.await?;
+			let status = response.status();
+			if !status.is_success() {
+				bail!("email webhook failure (status {})", status.as_u16());
+			}
+			Ok(())
+		})
+	}
+}
@@ -1,4 +1,16 @@
+pub mod analytics;
+pub mod audit;
+pub mod crawler;
+pub mod event;
+pub mod gdpr;
 pub mod geoip;
+pub mod mailer;
 pub mod newsletter;
 pub mod property;
+pub mod rate_limit;
+pub mod retention;
+pub mod secret;
+pub mod session;
+pub mod signature;
 pub mod uaparser;
+pub mod visitor;
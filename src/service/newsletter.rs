@@ -1,15 +1,64 @@
 //! Newsletter logic.
 
 use crate::util::PgResult;
-use chrono::Utc;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use uuid::Uuid;
 
-/// Insert a new email in the newsletter subscribers list.
-pub async fn insert_subscriber(db: &tokio_postgres::Client, email: &str) -> PgResult<()> {
+/// The duration after which an unconfirmed subscription expires.
+pub const CONFIRM_TTL: TimeDelta = TimeDelta::hours(48);
+
+/// Records a pending subscription for `email`, awaiting confirmation.
+///
+/// The function returns the confirmation token to send to the address. A previous pending
+/// subscription for the same address is replaced. If the address is already subscribed, the
+/// function returns `None`.
+pub async fn insert_pending(db: &tokio_postgres::Client, email: &str) -> PgResult<Option<Uuid>> {
+	let subscribed = db
+		.query_opt(
+			"SELECT email FROM newsletter_subscriber WHERE email = $1 AND unsubscribe_date IS NULL",
+			&[&email],
+		)
+		.await?
+		.is_some();
+	if subscribed {
+		return Ok(None);
+	}
+	let token = Uuid::new_v4();
+	let now = Utc::now().naive_utc();
+	let expires_at = now + CONFIRM_TTL;
+	db.execute(
+		r#"INSERT INTO newsletter_pending (email, token, request_date, expires_at) VALUES ($1, $2, $3, $4)
+			ON CONFLICT (email) DO UPDATE SET token = $2, request_date = $3, expires_at = $4"#,
+		&[&email, &token, &now, &expires_at],
+	)
+	.await?;
+	Ok(Some(token))
+}
+
+/// Confirms the pending subscription with the given token, adding its address to the
+/// subscribers list. If the address unsubscribed previously, it is subscribed again.
+///
+/// If no pending subscription matches the token, or if it expired, the function returns `false`.
+pub async fn confirm(db: &tokio_postgres::Client, token: &Uuid) -> PgResult<bool> {
+	let now = Utc::now().naive_utc();
+	let row = db
+		.query_one(
+			r#"WITH pending AS (DELETE FROM newsletter_pending WHERE token = $1 AND expires_at > $2 RETURNING email),
+				subscriber AS (INSERT INTO newsletter_subscriber (email, subscribe_date) SELECT email, $2 FROM pending
+					ON CONFLICT (email) DO UPDATE SET subscribe_date = EXCLUDED.subscribe_date, unsubscribe_date = NULL)
+				SELECT COUNT(*) FROM pending"#,
+			&[token, &now],
+		)
+		.await?;
+	Ok(row.get::<_, i64>(0) > 0)
+}
+
+/// Deletes expired pending subscriptions.
+pub async fn purge_pending(db: &tokio_postgres::Client) -> PgResult<()> {
 	let now = Utc::now().naive_utc();
 	db.execute(
-		"INSERT INTO newsletter_subscriber (email, subscribe_date)\
-			VALUES ($1, $2) ON CONFLICT DO NOTHING",
-		&[&email, &now],
+		"DELETE FROM newsletter_pending WHERE expires_at <= $1",
+		&[&now],
 	)
 	.await?;
 	Ok(())
@@ -33,3 +82,20 @@ pub async fn unsubscribe_from_token(
 		.await?;
 	Ok(n > 0)
 }
+
+/// Returns the emails of current newsletter subscribers, along with their subscription date,
+/// from oldest to newest.
+pub async fn list_subscribers(
+	db: &tokio_postgres::Client,
+) -> PgResult<Vec<(String, NaiveDateTime)>> {
+	let rows = db
+		.query(
+			"SELECT email, subscribe_date FROM newsletter_subscriber WHERE unsubscribe_date IS NULL ORDER BY subscribe_date",
+			&[],
+		)
+		.await?;
+	Ok(rows
+		.into_iter()
+		.map(|row| (row.get(0), row.get(1)))
+		.collect())
+}
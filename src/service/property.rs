@@ -1,20 +1,351 @@
 //! Property logic.
 
-use crate::util::PgResult;
+use crate::{service::rate_limit::Limits, util::PgResult};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+use tokio_postgres::GenericClient;
 use uuid::Uuid;
 
-/// Checks authentication for a property.
-pub async fn check_auth(
-	db: &tokio_postgres::Client,
+/// The maximum length of a property's name.
+const NAME_MAX_LEN: usize = 64;
+
+/// A property's settings.
+#[derive(Serialize)]
+pub struct Property {
+	/// The property's UUID.
+	pub uuid: Uuid,
+	/// The property's name.
+	pub name: String,
+	/// The origins allowed to submit data for the property from browsers.
+	pub origins: Vec<String>,
+	/// The hosts accesses may be sent to. If empty, any host is allowed.
+	///
+	/// A host starting with `*.` matches any of its subdomains.
+	pub hosts: Vec<String>,
+	/// Tells whether raw IP addresses and user agents are stored.
+	///
+	/// If not, they are only used to enrich accesses and compute visitor identifiers.
+	pub store_raw: bool,
+}
+
+impl Property {
+	/// Tells whether accesses sent to `host` are allowed for the property.
+	pub fn allows_host(&self, host: &str) -> bool {
+		if self.hosts.is_empty() {
+			return true;
+		}
+		let host = host.to_ascii_lowercase();
+		self.hosts.iter().any(|pattern| {
+			let pattern = pattern.to_ascii_lowercase();
+			match pattern.strip_prefix("*.") {
+				Some(domain) => host
+					.strip_suffix(domain)
+					.is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+				None => pattern == host,
+			}
+		})
+	}
+}
+
+/// Changes to apply to a property's settings. Fields that are `None` are left unchanged.
+#[derive(Deserialize)]
+pub struct PropertyUpdate {
+	/// The property's new name.
+	pub name: Option<String>,
+	/// The new allowed origins.
+	pub origins: Option<Vec<String>>,
+	/// The new allowed hosts.
+	pub hosts: Option<Vec<String>>,
+	/// Whether raw IP addresses and user agents are now stored.
+	pub store_raw: Option<bool>,
+}
+
+/// Tells whether the given property name is valid.
+pub fn validate_name(name: &str) -> bool {
+	!name.trim().is_empty() && name.chars().count() <= NAME_MAX_LEN
+}
+
+/// Creates a new property with the given settings and returns its UUID.
+///
+/// The property has no secret: one must be created with [`crate::service::secret::create`].
+pub async fn create(
+	db: &impl GenericClient,
+	name: &str,
+	origins: &[String],
+	hosts: &[String],
+	store_raw: bool,
+) -> PgResult<Uuid> {
+	let uuid = Uuid::new_v4();
+	db.execute(
+		"INSERT INTO property (uuid, name, origins, hosts, store_raw) VALUES ($1, $2, $3, $4, $5)",
+		&[&uuid, &name, &origins, &hosts, &store_raw],
+	)
+	.await?;
+	Ok(uuid)
+}
+
+/// Returns the property with the given UUID.
+pub async fn get(db: &tokio_postgres::Client, uuid: &Uuid) -> PgResult<Option<Property>> {
+	let row = db
+		.query_opt(
+			"SELECT uuid, name, origins, hosts, store_raw FROM property WHERE uuid = $1",
+			&[uuid],
+		)
+		.await?;
+	Ok(row.map(|row| Property {
+		uuid: row.get(0),
+		name: row.get(1),
+		origins: row.get(2),
+		hosts: row.get(3),
+		store_raw: row.get(4),
+	}))
+}
+
+/// Returns all properties, sorted by name.
+pub async fn list(db: &tokio_postgres::Client) -> PgResult<Vec<Property>> {
+	let rows = db
+		.query(
+			"SELECT uuid, name, origins, hosts, store_raw FROM property ORDER BY name",
+			&[],
+		)
+		.await?;
+	let properties = rows
+		.into_iter()
+		.map(|row| Property {
+			uuid: row.get(0),
+			name: row.get(1),
+			origins: row.get(2),
+			hosts: row.get(3),
+			store_raw: row.get(4),
+		})
+		.collect();
+	Ok(properties)
+}
+
+/// Updates the settings of the property with the given UUID.
+///
+/// If the property does not exist, the function returns `false`.
+pub async fn update(
+	db: &impl GenericClient,
+	uuid: &Uuid,
+	update: &PropertyUpdate,
+) -> PgResult<bool> {
+	let n = db
+		.execute(
+			r#"UPDATE property SET name = COALESCE($2, name), origins = COALESCE($3, origins), hosts = COALESCE($4, hosts), store_raw = COALESCE($5, store_raw)
+				WHERE uuid = $1"#,
+			&[
+				uuid,
+				&update.name,
+				&update.origins,
+				&update.hosts,
+				&update.store_raw,
+			],
+		)
+		.await?;
+	Ok(n > 0)
+}
+
+/// Sets the signing key of the property with the given UUID. If `None`, requests do not need to
+/// be signed anymore.
+///
+/// If the property does not exist, the function returns `false`.
+pub async fn set_signing_key(
+	db: &impl GenericClient,
 	uuid: &Uuid,
-	secret: &Uuid,
+	key: Option<&[u8]>,
 ) -> PgResult<bool> {
-	// TODO hash secret?
+	let n = db
+		.execute(
+			"UPDATE property SET signing_key = $2 WHERE uuid = $1",
+			&[uuid, &key],
+		)
+		.await?;
+	Ok(n > 0)
+}
+
+/// Returns the limits of the property with the given UUID, if it exists.
+pub async fn limits(db: &tokio_postgres::Client, uuid: &Uuid) -> PgResult<Option<Limits>> {
 	let row = db
 		.query_opt(
-			"SELECT uuid FROM property WHERE uuid = $1 AND secret = $2",
-			&[uuid, secret],
+			"SELECT rate_limit, monthly_quota FROM property WHERE uuid = $1",
+			&[uuid],
 		)
 		.await?;
-	Ok(row.is_some())
+	Ok(row.map(|row| Limits {
+		// Policies are validated before being stored
+		rate_limit: row
+			.get::<_, Option<String>>(0)
+			.and_then(|policy| policy.parse().ok()),
+		monthly_quota: row.get(1),
+	}))
+}
+
+/// Sets the limits of the property with the given UUID.
+///
+/// If the property does not exist, the function returns `false`.
+pub async fn set_limits(db: &impl GenericClient, uuid: &Uuid, limits: &Limits) -> PgResult<bool> {
+	let rate_limit = limits.rate_limit.map(|policy| policy.to_string());
+	let n = db
+		.execute(
+			"UPDATE property SET rate_limit = $2, monthly_quota = $3 WHERE uuid = $1",
+			&[uuid, &rate_limit, &limits.monthly_quota],
+		)
+		.await?;
+	Ok(n > 0)
+}
+
+/// Deletes the property with the given UUID, along with its secrets and all its data.
+///
+/// Everything is deleted by a single statement, so that no data is left without a property. If
+/// the property does not exist, the function returns `false`.
+pub async fn delete(db: &impl GenericClient, uuid: &Uuid) -> PgResult<bool> {
+	let row = db
+		.query_one(
+			r#"WITH secrets AS (DELETE FROM property_secret WHERE property = $1),
+				analytics AS (DELETE FROM analytics WHERE property = $1),
+				daily AS (DELETE FROM analytics_daily WHERE property = $1),
+				events AS (DELETE FROM event WHERE property = $1),
+				sessions AS (DELETE FROM visitor_session WHERE property = $1),
+				usage AS (DELETE FROM property_usage WHERE property = $1),
+				property AS (DELETE FROM property WHERE uuid = $1 RETURNING uuid)
+				SELECT COUNT(*) FROM property"#,
+			&[uuid],
+		)
+		.await?;
+	Ok(row.get::<_, i64>(0) > 0)
+}
+
+/// Returns the origins allowed for the property with the given UUID.
+///
+/// If the property does not exist, no origin is allowed.
+pub async fn origins(db: &tokio_postgres::Client, uuid: &Uuid) -> PgResult<Vec<String>> {
+	let row = db
+		.query_opt("SELECT origins FROM property WHERE uuid = $1", &[uuid])
+		.await?;
+	Ok(row.map(|row| row.get(0)).unwrap_or_default())
+}
+
+/// Returns the origins allowed for any property.
+pub async fn all_origins(db: &tokio_postgres::Client) -> PgResult<HashSet<String>> {
+	let rows = db
+		.query("SELECT DISTINCT UNNEST(origins) FROM property", &[])
+		.await?;
+	Ok(rows.into_iter().map(|row| row.get(0)).collect())
+}
+
+/// The duration for which allowed origins are cached.
+const ORIGINS_CACHE_TTL: Duration = Duration::from_mins(1);
+
+/// Cache of the origins allowed for cross-origin requests.
+///
+/// Changes made through the HTTP API invalidate the cache. Changes made from another process are
+/// taken into account once entries expire.
+#[derive(Default)]
+pub struct OriginCache {
+	/// The origins of each property, along with the date at which they have been retrieved.
+	entries: Mutex<HashMap<Uuid, (Vec<String>, Instant)>>,
+	/// The origins of all properties, along with the date at which they have been retrieved.
+	all: Mutex<Option<(HashSet<String>, Instant)>>,
+}
+
+impl OriginCache {
+	/// Tells whether `origin` is allowed for `property`, if the property's origins are cached.
+	pub fn allows(&self, property: &Uuid, origin: &str) -> Option<bool> {
+		let entries = self.entries.lock().unwrap();
+		let (origins, fetched_at) = entries.get(property)?;
+		(fetched_at.elapsed() < ORIGINS_CACHE_TTL).then(|| origins.iter().any(|o| o == origin))
+	}
+
+	/// Tells whether `origin` is allowed for any property, if the origins of all properties are
+	/// cached.
+	pub fn any_allows(&self, origin: &str) -> Option<bool> {
+		let all = self.all.lock().unwrap();
+		let (origins, fetched_at) = all.as_ref()?;
+		(fetched_at.elapsed() < ORIGINS_CACHE_TTL).then(|| origins.contains(origin))
+	}
+
+	/// Caches the origins of `property`.
+	pub fn set(&self, property: &Uuid, origins: Vec<String>) {
+		let mut entries = self.entries.lock().unwrap();
+		// Expired entries of properties that are not requested anymore are never read again
+		entries.retain(|_, (_, fetched_at)| fetched_at.elapsed() < ORIGINS_CACHE_TTL);
+		entries.insert(*property, (origins, Instant::now()));
+	}
+
+	/// Caches the origins of all properties.
+	pub fn set_all(&self, origins: HashSet<String>) {
+		*self.all.lock().unwrap() = Some((origins, Instant::now()));
+	}
+
+	/// Removes the cached origins of `property`, which have been created, changed or deleted.
+	pub fn invalidate(&self, property: &Uuid) {
+		self.entries.lock().unwrap().remove(property);
+		*self.all.lock().unwrap() = None;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn property(hosts: &[&str]) -> Property {
+		Property {
+			uuid: Uuid::nil(),
+			name: "test".to_owned(),
+			origins: vec![],
+			hosts: hosts.iter().map(|host| host.to_string()).collect(),
+			store_raw: false,
+		}
+	}
+
+	#[test]
+	fn any_host() {
+		let property = property(&[]);
+		assert!(property.allows_host("example.com"));
+		assert!(property.allows_host("localhost"));
+	}
+
+	#[test]
+	fn exact_host() {
+		let property = property(&["example.com"]);
+		assert!(property.allows_host("example.com"));
+		assert!(property.allows_host("Example.COM"));
+		assert!(!property.allows_host("www.example.com"));
+		assert!(!property.allows_host("example.org"));
+	}
+
+	#[test]
+	fn wildcard_host() {
+		let property = property(&["*.Example.com"]);
+		assert!(property.allows_host("www.example.com"));
+		assert!(property.allows_host("a.b.example.com"));
+		// The wildcard does not match the domain itself
+		assert!(!property.allows_host("example.com"));
+		assert!(!property.allows_host(".example.com"));
+		// Nor domains merely ending the same way
+		assert!(!property.allows_host("badexample.com"));
+		assert!(!property.allows_host("example.com.evil.org"));
+	}
+
+	#[test]
+	fn origin_cache() {
+		let cache = OriginCache::default();
+		let uuid = Uuid::new_v4();
+		assert_eq!(cache.allows(&uuid, "https://example.com"), None);
+		assert_eq!(cache.any_allows("https://example.com"), None);
+		cache.set(&uuid, vec!["https://example.com".to_owned()]);
+		cache.set_all(HashSet::from(["https://example.com".to_owned()]));
+		assert_eq!(cache.allows(&uuid, "https://example.com"), Some(true));
+		assert_eq!(cache.allows(&uuid, "https://example.org"), Some(false));
+		assert_eq!(cache.any_allows("https://example.com"), Some(true));
+		assert_eq!(cache.any_allows("https://example.org"), Some(false));
+		cache.invalidate(&uuid);
+		assert_eq!(cache.allows(&uuid, "https://example.com"), None);
+		assert_eq!(cache.any_allows("https://example.com"), None);
+	}
 }
@@ -0,0 +1,386 @@
+//! Rate limits and quotas.
+//!
+//! Requests are rate limited per peer, with a policy depending on the route. Requests pushing
+//! data are also rate limited per property, and count towards the property's monthly quota.
+
+use crate::util::PgResult;
+use chrono::{Datelike, Months, NaiveDate, Utc};
+use governor::{
+	NotUntil, Quota, RateLimiter,
+	clock::{self, Clock, DefaultClock},
+	middleware::{StateInformationMiddleware, StateSnapshot},
+	state::{InMemoryState, NotKeyed, keyed::DefaultKeyedStateStore},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::{
+	collections::HashMap,
+	fmt,
+	net::IpAddr,
+	num::NonZeroU32,
+	str::FromStr,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// Error returned when parsing an invalid [`Policy`].
+#[derive(Debug)]
+pub struct InvalidPolicy;
+
+impl fmt::Display for InvalidPolicy {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "invalid rate limit policy")
+	}
+}
+
+/// A rate limit policy, allowing at most `limit` requests per `period`.
+///
+/// Policies are written as `<limit>/<period>`, where the period is a number of seconds (`s`),
+/// minutes (`min`) or hours (`h`). For example: `5/s`, `100/10s` or `3/h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Policy {
+	/// The maximum number of requests in a period.
+	pub limit: NonZeroU32,
+	/// The period's duration.
+	pub period: Duration,
+}
+
+impl Policy {
+	/// Returns the quota corresponding to the policy.
+	///
+	/// Requests are replenished continuously, so that `limit` requests are allowed again after
+	/// `period`.
+	fn quota(&self) -> Quota {
+		Quota::with_period(self.period / self.limit.get())
+			.expect("validated policy")
+			.allow_burst(self.limit)
+	}
+}
+
+impl FromStr for Policy {
+	type Err = InvalidPolicy;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (limit, period) = s.trim().split_once('/').ok_or(InvalidPolicy)?;
+		let limit: NonZeroU32 = limit.parse().map_err(|_| InvalidPolicy)?;
+		let unit_start = period
+			.find(|c: char| !c.is_ascii_digit())
+			.ok_or(InvalidPolicy)?;
+		let (count, unit) = period.split_at(unit_start);
+		let count = match count {
+			"" => 1,
+			count => count.parse().map_err(|_| InvalidPolicy)?,
+		};
+		let unit = match unit {
+			"s" => Duration::from_secs(1),
+			"min" => Duration::from_mins(1),
+			"h" => Duration::from_hours(1),
+			_ => return Err(InvalidPolicy),
+		};
+		let period = unit.checked_mul(count).ok_or(InvalidPolicy)?;
+		// Requests must be replenished at a representable interval
+		if (period / limit.get()).is_zero() {
+			return Err(InvalidPolicy);
+		}
+		Ok(Self {
+			limit,
+			period,
+		})
+	}
+}
+
+impl fmt::Display for Policy {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}s", self.limit, self.period.as_secs())
+	}
+}
+
+impl<'de> Deserialize<'de> for Policy {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		s.parse().map_err(de::Error::custom)
+	}
+}
+
+impl Serialize for Policy {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+/// The state of a rate limit or quota, after a request.
+pub struct Status {
+	/// Tells whether the request is allowed.
+	pub allowed: bool,
+	/// The maximum number of requests.
+	pub limit: u64,
+	/// The number of remaining requests.
+	pub remaining: u64,
+	/// If the request is allowed, the time until the limit is fully reset. Otherwise, the time
+	/// until a request is allowed again.
+	pub reset: Duration,
+}
+
+/// A rate limiter collecting information on its state.
+type Limiter<K, S> = RateLimiter<K, S, DefaultClock, StateInformationMiddleware>;
+
+/// Returns the status corresponding to the result of a rate limiter's check.
+fn status<P: clock::Reference>(res: Result<StateSnapshot, NotUntil<P>>, now: P) -> Status {
+	match res {
+		Ok(snapshot) => {
+			let quota = snapshot.quota();
+			let limit = quota.burst_size().get();
+			let remaining = snapshot.remaining_burst_capacity();
+			Status {
+				allowed: true,
+				limit: limit.into(),
+				remaining: remaining.into(),
+				reset: quota.replenish_interval() * (limit - remaining),
+			}
+		}
+		Err(not_until) => Status {
+			allowed: false,
+			limit: not_until.quota().burst_size().get().into(),
+			remaining: 0,
+			reset: not_until.wait_time_from(now),
+		},
+	}
+}
+
+/// Rate limiter applying a policy to each peer.
+pub struct PeerLimiter(Limiter<IpAddr, DefaultKeyedStateStore<IpAddr>>);
+
+impl PeerLimiter {
+	/// Creates a rate limiter with the given policy.
+	pub fn new(policy: Policy) -> Self {
+		Self(RateLimiter::keyed(policy.quota()).with_middleware::<StateInformationMiddleware>())
+	}
+
+	/// Counts a request from `peer`.
+	pub fn check(&self, peer: IpAddr) -> Status {
+		let res = self.0.check_key(&peer);
+		status(res, self.0.clock().now())
+	}
+
+	/// Forgets peers whose limit is fully reset, to free memory.
+	pub fn retain_recent(&self) {
+		self.0.retain_recent();
+	}
+}
+
+/// The limits of a property, applying to requests pushing data.
+#[derive(Clone, Copy, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Limits {
+	/// The rate limit of requests, if any.
+	pub rate_limit: Option<Policy>,
+	/// The maximum number of records (accesses and events) per month, if any.
+	pub monthly_quota: Option<i64>,
+}
+
+/// The duration for which the limits of a property are cached.
+const LIMITS_CACHE_TTL: Duration = Duration::from_mins(1);
+
+/// The limits of a property, along with the state of its rate limit.
+struct Entry {
+	/// The property's limits.
+	limits: Limits,
+	/// The rate limiter, if the property has a rate limit.
+	limiter: Option<Limiter<NotKeyed, InMemoryState>>,
+	/// The date at which the limits have been retrieved.
+	fetched_at: Instant,
+}
+
+/// Rate limiters of properties, along with a cache of their limits.
+///
+/// Changes made through the HTTP API invalidate the property's entry. Changes made from another
+/// process are taken into account once the entry expires.
+#[derive(Default)]
+pub struct PropertyLimiter {
+	/// The entry of each property.
+	entries: Mutex<HashMap<Uuid, Entry>>,
+}
+
+impl PropertyLimiter {
+	/// Returns the cached limits of `property`, if any.
+	pub fn limits(&self, property: &Uuid) -> Option<Limits> {
+		let entries = self.entries.lock().unwrap();
+		let entry = entries.get(property)?;
+		(entry.fetched_at.elapsed() < LIMITS_CACHE_TTL).then_some(entry.limits)
+	}
+
+	/// Caches the limits of `property`.
+	///
+	/// The state of the property's rate limit is kept if its policy did not change.
+	pub fn set_limits(&self, property: &Uuid, limits: Limits) {
+		let mut entries = self.entries.lock().unwrap();
+		let fetched_at = Instant::now();
+		let unchanged = entries
+			.get_mut(property)
+			.filter(|entry| entry.limits.rate_limit == limits.rate_limit);
+		if let Some(entry) = unchanged {
+			entry.limits = limits;
+			entry.fetched_at = fetched_at;
+			return;
+		}
+		let limiter = limits.rate_limit.map(|policy| {
+			RateLimiter::direct(policy.quota()).with_middleware::<StateInformationMiddleware>()
+		});
+		entries.insert(
+			*property,
+			Entry {
+				limits,
+				limiter,
+				fetched_at,
+			},
+		);
+	}
+
+	/// Counts a request for `property`.
+	///
+	/// If the property has no rate limit, or if its limits are not cached, the function returns
+	/// `None`.
+	pub fn check(&self, property: &Uuid) -> Option<Status> {
+		let entries = self.entries.lock().unwrap();
+		let limiter = entries.get(property)?.limiter.as_ref()?;
+		let res = limiter.check();
+		Some(status(res, limiter.clock().now()))
+	}
+
+	/// Removes the entry of `property`.
+	pub fn invalidate(&self, property: &Uuid) {
+		self.entries.lock().unwrap().remove(property);
+	}
+}
+
+/// Returns the first day of the current month, along with the time until the next month.
+fn current_month() -> (NaiveDate, Duration) {
+	let now = Utc::now();
+	let month = now.date_naive().with_day(1).unwrap();
+	let next = (month + Months::new(1))
+		.and_time(Default::default())
+		.and_utc();
+	(month, (next - now).to_std().unwrap_or_default())
+}
+
+/// Reserves `n` records in the usage of `property` for the current month, if they fit in its
+/// monthly `quota`.
+///
+/// The check and the reservation are a single statement, so that concurrent requests cannot
+/// exceed the quota. If the property has a monthly quota, the function returns its status.
+/// Reserved records that end up not being stored must be released with [`release_usage`].
+pub async fn reserve_quota(
+	db: &tokio_postgres::Client,
+	property: &Uuid,
+	quota: Option<i64>,
+	n: i64,
+) -> PgResult<Option<Status>> {
+	let (month, reset) = current_month();
+	let Some(quota) = quota else {
+		db.execute(
+			r#"INSERT INTO property_usage (property, month, records) VALUES ($1, $2, $3)
+				ON CONFLICT (property, month) DO UPDATE SET records = property_usage.records + $3"#,
+			&[property, &month, &n],
+		)
+		.await?;
+		return Ok(None);
+	};
+	let row = db
+		.query_opt(
+			r#"INSERT INTO property_usage (property, month, records)
+				SELECT $1::UUID, $2::DATE, $3::BIGINT WHERE $3::BIGINT <= $4::BIGINT
+				ON CONFLICT (property, month) DO UPDATE SET records = property_usage.records + $3
+				WHERE property_usage.records + $3 <= $4
+				RETURNING records"#,
+			&[property, &month, &n, &quota],
+		)
+		.await?;
+	let (allowed, records) = match row {
+		Some(row) => (true, row.get(0)),
+		None => (false, usage(db, property).await?),
+	};
+	let remaining = quota.saturating_sub(records).max(0);
+	Ok(Some(Status {
+		allowed,
+		limit: quota as u64,
+		remaining: remaining as u64,
+		reset,
+	}))
+}
+
+/// Releases `n` records reserved with [`reserve_quota`] in the usage of `property` for the
+/// current month.
+pub async fn release_usage(db: &tokio_postgres::Client, property: &Uuid, n: i64) -> PgResult<()> {
+	let (month, _) = current_month();
+	db.execute(
+		"UPDATE property_usage SET records = GREATEST(records - $3, 0) WHERE property = $1 AND month = $2",
+		&[property, &month, &n],
+	)
+	.await?;
+	Ok(())
+}
+
+/// Returns the number of records of `property` for the current month.
+pub async fn usage(db: &tokio_postgres::Client, property: &Uuid) -> PgResult<i64> {
+	let (month, _) = current_month();
+	let row = db
+		.query_opt(
+			"SELECT records FROM property_usage WHERE property = $1 AND month = $2",
+			&[property, &month],
+		)
+		.await?;
+	Ok(row.map(|row| row.get(0)).unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn policy(limit: u32, period: Duration) -> Policy {
+		Policy {
+			limit: NonZeroU32::new(limit).unwrap(),
+			period,
+		}
+	}
+
+	#[test]
+	fn parse_policy() {
+		assert_eq!(
+			"5/s".parse::<Policy>().unwrap(),
+			policy(5, Duration::from_secs(1))
+		);
+		assert_eq!(
+			"100/10s".parse::<Policy>().unwrap(),
+			policy(100, Duration::from_secs(10))
+		);
+		assert_eq!(
+			"3/h".parse::<Policy>().unwrap(),
+			policy(3, Duration::from_hours(1))
+		);
+		assert_eq!(
+			" 20/5min ".parse::<Policy>().unwrap(),
+			policy(20, Duration::from_mins(5))
+		);
+	}
+
+	#[test]
+	fn parse_invalid_policy() {
+		for s in [
+			"", "5", "/s", "0/s", "-1/s", "5/", "5/10", "5/d", "5/ s", "5/0s", "x/s",
+		] {
+			assert!(s.parse::<Policy>().is_err(), "{s:?}");
+		}
+		// Overflowing periods
+		assert!("1/18446744073709551615h".parse::<Policy>().is_err());
+		// Requests replenished more often than every nanosecond
+		assert!("4000000000/s".parse::<Policy>().is_err());
+	}
+
+	#[test]
+	fn display_policy() {
+		for s in ["5/s", "3/h", "20/5min"] {
+			let policy: Policy = s.parse().unwrap();
+			assert_eq!(policy.to_string().parse::<Policy>().unwrap(), policy);
+		}
+		assert_eq!("3/h".parse::<Policy>().unwrap().to_string(), "3/3600s");
+	}
+}
@@ -0,0 +1,170 @@
+//! Per-property data retention.
+//!
+//! Each property defines how long data is kept, in days:
+//! - after the anonymization period, IP addresses and user agents are removed from accesses
+//! - after the raw data period, accesses are rolled up into daily aggregates and deleted, along
+//!   with events
+//! - after the aggregates period, daily aggregates are deleted too
+//!
+//! Rows are updated and deleted in bounded batches, so that the tables are never locked for long.
+
+use crate::util::PgResult;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use tokio_postgres::GenericClient;
+use tracing::info;
+use uuid::Uuid;
+
+/// The maximum number of rows updated or deleted by a single statement.
+const BATCH_SIZE: i64 = 10000;
+
+/// The retention policy of a property.
+pub struct Retention {
+	/// The property's UUID.
+	pub property: Uuid,
+	/// The number of days after which accesses are anonymized.
+	pub anonymize_days: Option<i32>,
+	/// The number of days after which raw accesses and events are deleted, keeping aggregates
+	/// only.
+	pub raw_days: Option<i32>,
+	/// The number of days after which aggregates are deleted.
+	pub aggregate_days: Option<i32>,
+}
+
+impl Retention {
+	/// Returns the date before which data is affected by a period of `days`, if any.
+	///
+	/// The date is truncated to the beginning of the day, so that days are processed as a whole.
+	fn cutoff(days: Option<i32>) -> Option<NaiveDateTime> {
+		let days = days?;
+		let date = Utc::now().date_naive() - TimeDelta::days(days.max(0) as _);
+		Some(date.and_hms_opt(0, 0, 0).unwrap())
+	}
+
+	/// Applies the policy.
+	pub async fn apply(&self, db: &mut tokio_postgres::Client) -> PgResult<()> {
+		if let Some(end) = Self::cutoff(self.anonymize_days) {
+			self.anonymize(db, &end).await?;
+		}
+		if let Some(end) = Self::cutoff(self.raw_days) {
+			self.aggregate(db, &end).await?;
+		}
+		if let Some(end) = Self::cutoff(self.aggregate_days) {
+			db.execute(
+				"DELETE FROM analytics_daily WHERE property = $1 AND day < $2",
+				&[&self.property, &end.date()],
+			)
+			.await?;
+		}
+		Ok(())
+	}
+
+	/// Removes identifying data from accesses before `end`.
+	async fn anonymize(&self, db: &tokio_postgres::Client, end: &NaiveDateTime) -> PgResult<()> {
+		loop {
+			let n = db
+				.execute(
+					r#"UPDATE analytics SET peer_addr = NULL, user_agent = NULL
+						WHERE ctid = ANY(ARRAY(
+							SELECT ctid FROM analytics
+							WHERE property = $1 AND date < $2 AND (peer_addr IS NOT NULL OR user_agent IS NOT NULL)
+							LIMIT $3
+						))"#,
+					&[&self.property, end, &BATCH_SIZE],
+				)
+				.await?;
+			if n < BATCH_SIZE as u64 {
+				break;
+			}
+		}
+		Ok(())
+	}
+
+	/// Rolls up accesses before `end` into daily aggregates, then deletes them along with
+	/// events.
+	///
+	/// Each day is aggregated and deleted in a single transaction, so that accesses are never
+	/// counted twice.
+	async fn aggregate(
+		&self,
+		db: &mut tokio_postgres::Client,
+		end: &NaiveDateTime,
+	) -> PgResult<()> {
+		// Days are processed one at a time, from oldest to newest
+		loop {
+			let row = db
+				.query_one(
+					"SELECT date_trunc('day', MIN(date)) FROM analytics WHERE property = $1 AND date < $2",
+					&[&self.property, end],
+				)
+				.await?;
+			let Some(day) = row.get::<_, Option<NaiveDateTime>>(0) else {
+				break;
+			};
+			let next = (day + TimeDelta::days(1)).min(*end);
+			info!(property = %self.property, %day, "retention: aggregate accesses");
+			// Accesses arriving late for a day that has already been aggregated are added to its
+			// aggregates. Their visitors may have been counted already
+			let tx = db.transaction().await?;
+			tx.execute(
+				r#"INSERT INTO analytics_daily (property, day, is_bot, views, visitors, opted_out)
+					SELECT $1::UUID, $4::DATE, is_bot, COUNT(*), COUNT(DISTINCT visitor_id), COUNT(*) FILTER (WHERE opt_out)
+					FROM analytics
+					WHERE property = $1 AND date >= $2 AND date < $3
+					GROUP BY is_bot
+					ON CONFLICT (property, day, is_bot) DO UPDATE SET
+						views = analytics_daily.views + EXCLUDED.views,
+						visitors = analytics_daily.visitors + EXCLUDED.visitors,
+						opted_out = analytics_daily.opted_out + EXCLUDED.opted_out"#,
+				&[&self.property, &day, &next, &day.date()],
+			)
+			.await?;
+			self.delete("analytics", &tx, &next).await?;
+			tx.commit().await?;
+		}
+		self.delete("event", &*db, end).await
+	}
+
+	/// Deletes rows of `table` before `end`.
+	async fn delete(
+		&self,
+		table: &str,
+		db: &impl GenericClient,
+		end: &NaiveDateTime,
+	) -> PgResult<()> {
+		let query = format!(
+			r#"DELETE FROM {table}
+				WHERE ctid = ANY(ARRAY(
+					SELECT ctid FROM {table} WHERE property = $1 AND date < $2 LIMIT $3
+				))"#
+		);
+		loop {
+			let n = db
+				.execute(query.as_str(), &[&self.property, end, &BATCH_SIZE])
+				.await?;
+			if n < BATCH_SIZE as u64 {
+				break;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Returns the retention policies of all properties.
+pub async fn policies(db: &tokio_postgres::Client) -> PgResult<Vec<Retention>> {
+	let rows = db
+		.query(
+			"SELECT uuid, retention_anonymize_days, retention_raw_days, retention_aggregate_days FROM property",
+			&[],
+		)
+		.await?;
+	let policies = rows
+		.into_iter()
+		.map(|row| Retention {
+			property: row.get(0),
+			anonymize_days: row.get(1),
+			raw_days: row.get(2),
+			aggregate_days: row.get(3),
+		})
+		.collect();
+	Ok(policies)
+}
@@ -0,0 +1,283 @@
+//! Property secrets.
+//!
+//! Secrets are stored as salted argon2 hashes. A property may have several active secrets, so
+//! that a new secret can be rolled out to services before the old one is revoked.
+
+use crate::util::PgResult;
+use argon2::{
+	Argon2,
+	password_hash::{
+		PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng,
+	},
+};
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use gateway_api::util::{date_format, option_date_format};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+	collections::HashMap,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+use tokio::task::spawn_blocking;
+use tokio_postgres::GenericClient;
+use tracing::info;
+use uuid::Uuid;
+
+/// Information about a secret, without the secret itself.
+#[derive(Serialize)]
+pub struct SecretInfo {
+	/// The secret's identifier.
+	pub id: Uuid,
+	/// The date at which the secret has been created.
+	#[serde(with = "date_format")]
+	pub created_at: DateTime<Utc>,
+	/// The date after which the secret cannot be used anymore, if any.
+	#[serde(with = "option_date_format")]
+	pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Hashes the given secret.
+///
+/// Hashing is expensive, so it runs on a blocking thread.
+async fn hash(secret: String) -> String {
+	spawn_blocking(move || {
+		let salt = SaltString::generate(&mut OsRng);
+		Argon2::default()
+			.hash_password(secret.as_bytes(), &salt)
+			.expect("secret hashing")
+			.to_string()
+	})
+	.await
+	.expect("secret hashing task")
+}
+
+/// Returns the index of the hash `secret` matches in `hashes`, if any.
+///
+/// Verification is expensive, so it runs on a blocking thread.
+async fn verify(secret: String, hashes: Vec<String>) -> Option<usize> {
+	spawn_blocking(move || {
+		let argon2 = Argon2::default();
+		hashes.iter().position(|hash| {
+			PasswordHash::new(hash)
+				.is_ok_and(|hash| argon2.verify_password(secret.as_bytes(), &hash).is_ok())
+		})
+	})
+	.await
+	.ok()
+	.flatten()
+}
+
+/// A secret that has been successfully checked.
+#[derive(Clone)]
+pub struct Verified {
+	/// The date after which the secret cannot be used anymore, if any.
+	pub expires_at: Option<DateTime<Utc>>,
+	/// The signing key of the secret's property, if it requires signed requests.
+	pub signing_key: Option<Vec<u8>>,
+}
+
+/// Checks whether `secret` is one of the active secrets of `property`.
+///
+/// If not, the function returns `None`.
+pub async fn check(
+	db: &tokio_postgres::Client,
+	property: &Uuid,
+	secret: &str,
+) -> PgResult<Option<Verified>> {
+	let now = Utc::now().naive_utc();
+	let rows = db
+		.query(
+			r#"SELECT s.hash, s.expires_at, p.signing_key
+				FROM property_secret s JOIN property p ON p.uuid = s.property
+				WHERE s.property = $1 AND (s.expires_at IS NULL OR s.expires_at > $2)"#,
+			&[property, &now],
+		)
+		.await?;
+	let signing_key: Option<Vec<u8>> = rows.first().and_then(|row| row.get(2));
+	let (hashes, expiries): (Vec<String>, Vec<Option<NaiveDateTime>>) = rows
+		.into_iter()
+		.map(|row| {
+			(
+				row.get::<_, String>(0),
+				row.get::<_, Option<NaiveDateTime>>(1),
+			)
+		})
+		.unzip();
+	let verified = verify(secret.to_owned(), hashes).await.map(|i| Verified {
+		expires_at: expiries[i].map(|date| date.and_utc()),
+		signing_key,
+	});
+	Ok(verified)
+}
+
+/// The duration for which a successful authentication is cached.
+const AUTH_CACHE_TTL: Duration = Duration::from_mins(5);
+/// The duration for which a failed authentication is cached.
+const AUTH_CACHE_NEGATIVE_TTL: Duration = Duration::from_secs(30);
+/// The maximum number of entries in the authentication cache.
+const AUTH_CACHE_CAPACITY: usize = 4096;
+
+/// In-process cache of authentication results, so that authenticating does not require a
+/// database round trip and a hash verification on every request.
+///
+/// Secrets are not stored in clear: entries are keyed by a digest of the secret. Failed
+/// authentications are cached as well, for a shorter duration. Successful ones carry the
+/// property's signing key, so that checking signatures does not require a round trip either.
+///
+/// Changes made through the HTTP API invalidate the property's entries. Changes made from
+/// another process (such as `gateway-admin`) are taken into account once entries expire.
+#[derive(Default)]
+pub struct AuthCache {
+	/// The result of each authentication, along with its expiry date.
+	entries: Mutex<HashMap<(Uuid, [u8; 32]), AuthEntry>>,
+}
+
+/// The result of an authentication, along with its expiry date.
+type AuthEntry = (Option<Verified>, Instant);
+
+impl AuthCache {
+	/// Returns the key of the entry for the given credentials.
+	fn key(property: &Uuid, secret: &str) -> (Uuid, [u8; 32]) {
+		(*property, Sha256::digest(secret).into())
+	}
+
+	/// Returns the cached result of the authentication with the given credentials, if any.
+	pub fn get(&self, property: &Uuid, secret: &str) -> Option<Option<Verified>> {
+		let entries = self.entries.lock().unwrap();
+		let (verified, expires_at) = entries.get(&Self::key(property, secret))?;
+		(*expires_at > Instant::now()).then(|| verified.clone())
+	}
+
+	/// Caches the result of an authentication.
+	///
+	/// A successful authentication is not cached beyond the secret's own expiry.
+	pub fn insert(&self, property: &Uuid, secret: &str, verified: Option<Verified>) {
+		let now = Instant::now();
+		let ttl = match &verified {
+			Some(Verified {
+				expires_at: Some(expires_at),
+				..
+			}) => {
+				// The secret expired in the meantime
+				let Ok(remaining) = (*expires_at - Utc::now()).to_std() else {
+					return;
+				};
+				remaining.min(AUTH_CACHE_TTL)
+			}
+			Some(_) => AUTH_CACHE_TTL,
+			None => AUTH_CACHE_NEGATIVE_TTL,
+		};
+		let mut entries = self.entries.lock().unwrap();
+		if entries.len() >= AUTH_CACHE_CAPACITY {
+			entries.retain(|_, (_, expires_at)| *expires_at > now);
+			if entries.len() >= AUTH_CACHE_CAPACITY {
+				entries.clear();
+			}
+		}
+		entries.insert(Self::key(property, secret), (verified, now + ttl));
+	}
+
+	/// Removes all entries of `property`.
+	pub fn invalidate(&self, property: &Uuid) {
+		let mut entries = self.entries.lock().unwrap();
+		entries.retain(|(uuid, _), _| uuid != property);
+	}
+}
+
+/// Creates a new secret for `property`.
+///
+/// The function returns the secret's identifier along with the secret itself, which cannot be
+/// retrieved afterwards. If the property does not exist, the function returns `None`.
+pub async fn create(db: &impl GenericClient, property: &Uuid) -> PgResult<Option<(Uuid, String)>> {
+	let id = Uuid::new_v4();
+	let secret = Uuid::new_v4().to_string();
+	let hash = hash(secret.clone()).await;
+	let now = Utc::now().naive_utc();
+	let n = db
+		.execute(
+			r#"INSERT INTO property_secret (id, property, hash, created_at)
+				SELECT $1, uuid, $3, $4 FROM property WHERE uuid = $2"#,
+			&[&id, property, &hash, &now],
+		)
+		.await?;
+	Ok((n > 0).then_some((id, secret)))
+}
+
+/// Returns the secrets of `property`, including expired ones.
+pub async fn list(db: &tokio_postgres::Client, property: &Uuid) -> PgResult<Vec<SecretInfo>> {
+	let rows = db
+		.query(
+			"SELECT id, created_at, expires_at FROM property_secret WHERE property = $1 ORDER BY created_at",
+			&[property],
+		)
+		.await?;
+	let secrets = rows
+		.into_iter()
+		.map(|row| SecretInfo {
+			id: row.get(0),
+			created_at: row.get::<_, NaiveDateTime>(1).and_utc(),
+			expires_at: row
+				.get::<_, Option<NaiveDateTime>>(2)
+				.map(|date| date.and_utc()),
+		})
+		.collect();
+	Ok(secrets)
+}
+
+/// Revokes the secret `id` of `property`, after the given grace period.
+///
+/// A secret that already expires earlier keeps its expiry date. If the secret does not exist,
+/// the function returns `false`.
+pub async fn revoke(
+	db: &impl GenericClient,
+	property: &Uuid,
+	id: &Uuid,
+	grace: TimeDelta,
+) -> PgResult<bool> {
+	let expires_at = Utc::now().naive_utc() + grace;
+	let n = db
+		.execute(
+			"UPDATE property_secret SET expires_at = LEAST(COALESCE(expires_at, $3), $3) WHERE property = $1 AND id = $2",
+			&[property, id, &expires_at],
+		)
+		.await?;
+	Ok(n > 0)
+}
+
+/// Moves plaintext secrets remaining from before secrets were hashed to the secrets table.
+///
+/// Each secret is moved in a transaction, so that it is never stored in both places.
+pub async fn migrate_plaintext(db: &mut tokio_postgres::Client) -> PgResult<()> {
+	let rows = db
+		.query(
+			"SELECT uuid, secret FROM property WHERE secret IS NOT NULL",
+			&[],
+		)
+		.await?;
+	for row in rows {
+		let property: Uuid = row.get(0);
+		let secret: Uuid = row.get(1);
+		info!(%property, "migrate plaintext secret");
+		let hash = hash(secret.to_string()).await;
+		let now = Utc::now().naive_utc();
+		let tx = db.transaction().await?;
+		// Another instance may have migrated the secret in the meantime
+		let n = tx
+			.execute(
+				"UPDATE property SET secret = NULL WHERE uuid = $1 AND secret = $2",
+				&[&property, &secret],
+			)
+			.await?;
+		if n == 0 {
+			continue;
+		}
+		tx.execute(
+			"INSERT INTO property_secret (id, property, hash, created_at) VALUES ($1, $2, $3, $4)",
+			&[&Uuid::new_v4(), &property, &hash, &now],
+		)
+		.await?;
+		tx.commit().await?;
+	}
+	Ok(())
+}
@@ -0,0 +1,214 @@
+//! Grouping of accesses into visits (sessions).
+
+use crate::util::PgResult;
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use std::{collections::HashMap, time::Duration};
+use uuid::Uuid;
+
+/// Duration of inactivity after which a visitor's next access starts a new session.
+pub const SESSION_TIMEOUT: Duration = Duration::from_mins(30);
+
+/// A visit to be assigned a session.
+pub struct Visit<'v> {
+	/// The identifier of the visitor.
+	pub visitor_id: Option<&'v [u8]>,
+	/// The date of the access.
+	pub date: NaiveDateTime,
+	/// Tells whether the visitor is a bot.
+	///
+	/// Once a visitor has been detected as a bot, all its subsequent visits are flagged as well.
+	pub is_bot: bool,
+	/// The session the visit belongs to, set by [`assign`].
+	pub session: Option<Uuid>,
+}
+
+/// The state of a visitor: its current session, its last activity and whether it is a bot.
+type VisitorState = (Uuid, NaiveDateTime, bool);
+
+/// Assigns sessions to `visits` given the `current` state of the visitors, which is updated.
+fn advance(current: &mut HashMap<Vec<u8>, VisitorState>, visits: &mut [Visit<'_>]) {
+	// Visits are processed in chronological order
+	let mut order: Vec<usize> = (0..visits.len()).collect();
+	order.sort_unstable_by_key(|i| visits[*i].date);
+	let timeout = TimeDelta::from_std(SESSION_TIMEOUT).unwrap();
+	for i in order {
+		let visit = &mut visits[i];
+		let Some(visitor_id) = visit.visitor_id else {
+			continue;
+		};
+		let (session, last_seen, is_bot) = current
+			.entry(visitor_id.to_vec())
+			.or_insert_with(|| (Uuid::new_v4(), visit.date, false));
+		if visit.date > *last_seen + timeout {
+			*session = Uuid::new_v4();
+		}
+		*last_seen = visit.date.max(*last_seen);
+		*is_bot |= visit.is_bot;
+		visit.session = Some(*session);
+	}
+	// Bot detection may happen after the visitor's first visits in the batch
+	for visit in visits.iter_mut() {
+		if let Some((_, _, is_bot)) = visit.visitor_id.and_then(|id| current.get(id)) {
+			visit.is_bot |= *is_bot;
+		}
+	}
+}
+
+/// Assigns a session to each visit of `property`.
+///
+/// A visit belongs to the visitor's current session unless it happens more than
+/// [`SESSION_TIMEOUT`] after the visitor's last activity, in which case a new session is started.
+/// Late visits (e.g. replayed from a client's spool) are kept in the current session. Visits
+/// without a visitor identifier are not assigned any session.
+///
+/// The state of the visitors is locked for the duration of the assignment, so that concurrent
+/// batches for the same visitor are serialized.
+pub async fn assign(
+	db: &mut tokio_postgres::Client,
+	property: &Uuid,
+	visits: &mut [Visit<'_>],
+) -> PgResult<()> {
+	let mut first_seen: HashMap<&[u8], NaiveDateTime> = HashMap::new();
+	for visit in visits.iter() {
+		if let Some(visitor_id) = visit.visitor_id {
+			first_seen
+				.entry(visitor_id)
+				.and_modify(|date| *date = visit.date.min(*date))
+				.or_insert(visit.date);
+		}
+	}
+	if first_seen.is_empty() {
+		return Ok(());
+	}
+	// Rows are created in a consistent order to avoid deadlocks between concurrent batches
+	let mut first_seen: Vec<_> = first_seen.into_iter().collect();
+	first_seen.sort_unstable();
+	let visitor_ids: Vec<&[u8]> = first_seen.iter().map(|(id, _)| *id).collect();
+	let new_sessions: Vec<Uuid> = first_seen.iter().map(|_| Uuid::new_v4()).collect();
+	let first_dates: Vec<NaiveDateTime> = first_seen.iter().map(|(_, date)| *date).collect();
+	let tx = db.transaction().await?;
+	// Make sure every visitor has a state, so that it can be locked
+	tx.execute(
+		r#"INSERT INTO visitor_session (property, visitor_id, session, last_seen, is_bot)
+			SELECT $1::UUID, *, FALSE FROM UNNEST($2::BYTEA[], $3::UUID[], $4::TIMESTAMP[])
+			ON CONFLICT (property, visitor_id) DO NOTHING"#,
+		&[property, &visitor_ids, &new_sessions, &first_dates],
+	)
+	.await?;
+	// Retrieve and lock the current state of each visitor
+	let rows = tx
+		.query(
+			"SELECT visitor_id, session, last_seen, is_bot FROM visitor_session WHERE property = $1 AND visitor_id = ANY($2) ORDER BY visitor_id FOR UPDATE",
+			&[property, &visitor_ids],
+		)
+		.await?;
+	let mut current: HashMap<Vec<u8>, VisitorState> = rows
+		.into_iter()
+		.map(|row| (row.get(0), (row.get(1), row.get(2), row.get(3))))
+		.collect();
+	advance(&mut current, visits);
+	// Save the current state of each visitor
+	let mut visitor_id = Vec::with_capacity(current.len());
+	let mut session = Vec::with_capacity(current.len());
+	let mut last_seen = Vec::with_capacity(current.len());
+	let mut is_bot = Vec::with_capacity(current.len());
+	for (v, (s, l, b)) in &current {
+		visitor_id.push(v.as_slice());
+		session.push(*s);
+		last_seen.push(*l);
+		is_bot.push(*b);
+	}
+	tx.execute(
+		r#"INSERT INTO visitor_session (property, visitor_id, session, last_seen, is_bot)
+			SELECT $1::UUID, * FROM UNNEST($2::BYTEA[], $3::UUID[], $4::TIMESTAMP[], $5::BOOLEAN[])
+			ON CONFLICT (property, visitor_id) DO UPDATE SET session = EXCLUDED.session, last_seen = EXCLUDED.last_seen, is_bot = EXCLUDED.is_bot"#,
+		&[property, &visitor_id, &session, &last_seen, &is_bot],
+	)
+	.await?;
+	tx.commit().await
+}
+
+/// Removes the state of visitors whose session has expired.
+pub async fn purge(db: &tokio_postgres::Client) -> PgResult<()> {
+	let end = Utc::now().naive_utc() - SESSION_TIMEOUT;
+	db.execute("DELETE FROM visitor_session WHERE last_seen < $1", &[&end])
+		.await?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::NaiveDate;
+
+	fn date(h: u32, m: u32) -> NaiveDateTime {
+		NaiveDate::from_ymd_opt(2025, 1, 1)
+			.unwrap()
+			.and_hms_opt(h, m, 0)
+			.unwrap()
+	}
+
+	fn visit(visitor_id: &[u8], date: NaiveDateTime) -> Visit<'_> {
+		Visit {
+			visitor_id: Some(visitor_id),
+			date,
+			is_bot: false,
+			session: None,
+		}
+	}
+
+	#[test]
+	fn within_timeout_keeps_session() {
+		let session = Uuid::new_v4();
+		let mut current = HashMap::from([(b"a".to_vec(), (session, date(10, 0), false))]);
+		let mut visits = [visit(b"a", date(10, 20)), visit(b"a", date(10, 45))];
+		advance(&mut current, &mut visits);
+		assert_eq!(visits[0].session, Some(session));
+		assert_eq!(visits[1].session, Some(session));
+		assert_eq!(current[b"a".as_slice()].1, date(10, 45));
+	}
+
+	#[test]
+	fn after_timeout_starts_session() {
+		let session = Uuid::new_v4();
+		let mut current = HashMap::from([(b"a".to_vec(), (session, date(10, 0), false))]);
+		let mut visits = [visit(b"a", date(11, 0))];
+		advance(&mut current, &mut visits);
+		assert_ne!(visits[0].session, Some(session));
+		assert_eq!(current[b"a".as_slice()].0, visits[0].session.unwrap());
+	}
+
+	#[test]
+	fn late_visit_keeps_session() {
+		let session = Uuid::new_v4();
+		let mut current = HashMap::from([(b"a".to_vec(), (session, date(12, 0), false))]);
+		let mut visits = [visit(b"a", date(10, 0))];
+		advance(&mut current, &mut visits);
+		assert_eq!(visits[0].session, Some(session));
+		assert_eq!(current[b"a".as_slice()], (session, date(12, 0), false));
+	}
+
+	#[test]
+	fn bot_flag_propagates() {
+		let mut current = HashMap::new();
+		let mut visits = [visit(b"a", date(10, 0)), visit(b"a", date(10, 5))];
+		visits[1].is_bot = true;
+		advance(&mut current, &mut visits);
+		assert!(visits[0].is_bot);
+		assert_eq!(visits[0].session, visits[1].session);
+	}
+
+	#[test]
+	fn anonymous_visit_has_no_session() {
+		let mut current = HashMap::new();
+		let mut visits = [Visit {
+			visitor_id: None,
+			date: date(10, 0),
+			is_bot: false,
+			session: None,
+		}];
+		advance(&mut current, &mut visits);
+		assert_eq!(visits[0].session, None);
+		assert!(current.is_empty());
+	}
+}
@@ -0,0 +1,191 @@
+//! Verification of signed requests.
+//!
+//! Properties with a signing key require requests pushing data to be signed (see
+//! [`gateway_api::sign`]). A request is accepted only if its timestamp is within
+//! [`REPLAY_WINDOW`] of the current time and its nonce has not been seen in that window.
+
+use axum::http::HeaderMap;
+use chrono::Utc;
+use gateway_api::sign;
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+use uuid::Uuid;
+
+/// The maximum difference between the timestamp of a request and the current time.
+pub const REPLAY_WINDOW: Duration = Duration::from_mins(5);
+
+/// The reason why a signature has been rejected.
+#[derive(Debug)]
+pub enum SignatureError {
+	/// The request is not signed.
+	Missing,
+	/// The request's timestamp is outside the replay window.
+	Expired,
+	/// The signature does not match the request.
+	Invalid,
+	/// The request's nonce has already been used.
+	Replayed,
+}
+
+/// Nonces of recently accepted requests.
+///
+/// Nonces can be forgotten once their timestamp leaves the replay window, since requests with
+/// such a timestamp are rejected anyway. This is done by [`NonceCache::prune`], which must be
+/// called periodically.
+#[derive(Default)]
+pub struct NonceCache {
+	/// The timestamp of each nonce, per property.
+	nonces: Mutex<HashMap<(Uuid, String), i64>>,
+}
+
+impl NonceCache {
+	/// Records a nonce.
+	///
+	/// If the nonce has already been recorded for `property`, the function returns `false`.
+	fn insert(&self, property: &Uuid, nonce: &str, timestamp: i64) -> bool {
+		self.nonces
+			.lock()
+			.unwrap()
+			.insert((*property, nonce.to_owned()), timestamp)
+			.is_none()
+	}
+
+	/// Forgets nonces whose timestamp left the replay window, to free memory.
+	pub fn prune(&self) {
+		let now = Utc::now().timestamp();
+		let window = REPLAY_WINDOW.as_secs() as i64;
+		let mut nonces = self.nonces.lock().unwrap();
+		nonces.retain(|_, ts| now - *ts <= window);
+	}
+
+	/// Checks the signature of a request pushing data to `endpoint` for `property`, with the
+	/// property's signing `key`.
+	pub fn check(
+		&self,
+		key: &[u8],
+		property: &Uuid,
+		endpoint: &str,
+		headers: &HeaderMap,
+		body: &[u8],
+	) -> Result<(), SignatureError> {
+		let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+		let (Some(timestamp), Some(nonce), Some(signature)) = (
+			header(sign::TIMESTAMP_HEADER),
+			header(sign::NONCE_HEADER),
+			header(sign::SIGNATURE_HEADER),
+		) else {
+			return Err(SignatureError::Missing);
+		};
+		let timestamp: i64 = timestamp.parse().map_err(|_| SignatureError::Invalid)?;
+		let now = Utc::now().timestamp();
+		if now.abs_diff(timestamp) > REPLAY_WINDOW.as_secs() {
+			return Err(SignatureError::Expired);
+		}
+		if !sign::verify(key, timestamp, nonce, endpoint, body, signature) {
+			return Err(SignatureError::Invalid);
+		}
+		// Record the nonce only once the signature is known to be valid, so that forged requests
+		// cannot fill the cache
+		if !self.insert(property, nonce, timestamp) {
+			return Err(SignatureError::Replayed);
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const KEY: &[u8] = b"key";
+	const ENDPOINT: &str = "/access";
+	const BODY: &[u8] = b"[]";
+
+	fn headers(timestamp: i64, nonce: &str, signature: &str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert(
+			sign::TIMESTAMP_HEADER,
+			timestamp.to_string().parse().unwrap(),
+		);
+		headers.insert(sign::NONCE_HEADER, nonce.parse().unwrap());
+		headers.insert(sign::SIGNATURE_HEADER, signature.parse().unwrap());
+		headers
+	}
+
+	fn signed(timestamp: i64, nonce: &str) -> HeaderMap {
+		let signature = sign::sign(KEY, timestamp, nonce, ENDPOINT, BODY);
+		headers(timestamp, nonce, &signature)
+	}
+
+	#[test]
+	fn valid() {
+		let cache = NonceCache::default();
+		let headers = signed(Utc::now().timestamp(), "a");
+		let res = cache.check(KEY, &Uuid::nil(), ENDPOINT, &headers, BODY);
+		assert!(res.is_ok());
+	}
+
+	#[test]
+	fn missing() {
+		let cache = NonceCache::default();
+		let res = cache.check(KEY, &Uuid::nil(), ENDPOINT, &HeaderMap::new(), BODY);
+		assert!(matches!(res, Err(SignatureError::Missing)));
+	}
+
+	#[test]
+	fn replayed() {
+		let cache = NonceCache::default();
+		let headers = signed(Utc::now().timestamp(), "a");
+		assert!(
+			cache
+				.check(KEY, &Uuid::nil(), ENDPOINT, &headers, BODY)
+				.is_ok()
+		);
+		let res = cache.check(KEY, &Uuid::nil(), ENDPOINT, &headers, BODY);
+		assert!(matches!(res, Err(SignatureError::Replayed)));
+		// Nonces are per property
+		let res = cache.check(KEY, &Uuid::max(), ENDPOINT, &headers, BODY);
+		assert!(res.is_ok());
+	}
+
+	#[test]
+	fn expired() {
+		let cache = NonceCache::default();
+		let window = REPLAY_WINDOW.as_secs() as i64;
+		for timestamp in [
+			Utc::now().timestamp() - window - 10,
+			Utc::now().timestamp() + window + 10,
+			i64::MIN,
+			i64::MAX,
+		] {
+			let headers = signed(timestamp, "a");
+			let res = cache.check(KEY, &Uuid::nil(), ENDPOINT, &headers, BODY);
+			assert!(matches!(res, Err(SignatureError::Expired)));
+		}
+	}
+
+	#[test]
+	fn invalid() {
+		let cache = NonceCache::default();
+		let timestamp = Utc::now().timestamp();
+		let headers = signed(timestamp, "a");
+		let res = cache.check(b"other", &Uuid::nil(), ENDPOINT, &headers, BODY);
+		assert!(matches!(res, Err(SignatureError::Invalid)));
+		let res = cache.check(KEY, &Uuid::nil(), "/event", &headers, BODY);
+		assert!(matches!(res, Err(SignatureError::Invalid)));
+		// A rejected request does not consume its nonce
+		let res = cache.check(KEY, &Uuid::nil(), ENDPOINT, &headers, BODY);
+		assert!(res.is_ok());
+	}
+
+	#[test]
+	fn prune() {
+		let cache = NonceCache::default();
+		let window = REPLAY_WINDOW.as_secs() as i64;
+		let now = Utc::now().timestamp();
+		assert!(cache.insert(&Uuid::nil(), "old", now - window - 10));
+		assert!(cache.insert(&Uuid::nil(), "new", now));
+		cache.prune();
+		assert!(cache.insert(&Uuid::nil(), "old", now));
+		assert!(!cache.insert(&Uuid::nil(), "new", now));
+	}
+}
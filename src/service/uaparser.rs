@@ -29,6 +29,13 @@ impl Renewable for UaParser {
 	}
 }
 
+impl UserDevice {
+	/// Tells whether the device is a known bot.
+	pub fn is_spider(&self) -> bool {
+		self.device_family == "Spider"
+	}
+}
+
 impl UaParser {
 	pub fn resolve(&self, user_agent: &str) -> UserDevice {
 		let parsed = self.0.parse(user_agent);
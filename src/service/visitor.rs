@@ -0,0 +1,53 @@
+//! Cookieless visitor identification.
+//!
+//! Visitors are identified by hashing their IP address and user agent with a salt that changes
+//! every day. Once a salt is deleted, the identifiers computed with it cannot be linked back to
+//! visitors anymore.
+
+use crate::util::PgResult;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use uuid::Uuid;
+
+/// Returns the salt of the current day, creating it if it does not exist yet.
+pub async fn daily_salt(db: &tokio_postgres::Client) -> PgResult<Vec<u8>> {
+	let today = Utc::now().date_naive();
+	let salt = rand::random::<[u8; 32]>();
+	let row = db
+		.query_one(
+			r#"INSERT INTO visitor_salt (day, salt) VALUES ($1, $2)
+				ON CONFLICT (day) DO UPDATE SET day = EXCLUDED.day
+				RETURNING salt"#,
+			&[&today, &salt.as_slice()],
+		)
+		.await?;
+	Ok(row.get(0))
+}
+
+/// Deletes the salts of previous days.
+pub async fn purge_salts(db: &tokio_postgres::Client) -> PgResult<()> {
+	let today = Utc::now().date_naive();
+	db.execute("DELETE FROM visitor_salt WHERE day < $1", &[&today])
+		.await?;
+	Ok(())
+}
+
+/// Returns the identifier of the visitor of `property` with the given IP address and user agent.
+///
+/// If the IP address is unknown, visitors cannot be told apart and the function returns `None`.
+pub fn visitor_id(
+	salt: &[u8],
+	property: &Uuid,
+	peer_addr: Option<IpAddr>,
+	user_agent: Option<&str>,
+) -> Option<Vec<u8>> {
+	let peer_addr = peer_addr?;
+	let mut hasher = Sha256::new();
+	hasher.update(salt);
+	hasher.update(property.as_bytes());
+	hasher.update(peer_addr.to_string());
+	hasher.update([0]);
+	hasher.update(user_agent.unwrap_or_default());
+	Some(hasher.finalize().to_vec())
+}
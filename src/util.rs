@@ -4,10 +4,13 @@ use anyhow::{Result, bail};
 use flate2::read::GzDecoder;
 use regex::Regex;
 use std::{
+	borrow::Cow,
 	io::Read,
-	sync::{OnceLock, RwLock, RwLockReadGuard},
+	ops::{Deref, DerefMut},
+	sync::{Mutex, OnceLock, RwLock, RwLockReadGuard},
 };
-use tracing::trace;
+use tokio_postgres::{Client, NoTls};
+use tracing::{trace, warn};
 
 /// Result with PostgreSQL error.
 pub type PgResult<T> = std::result::Result<T, tokio_postgres::Error>;
@@ -22,6 +25,26 @@ pub fn validate_email(email: &str) -> bool {
 	regex.is_match(email)
 }
 
+/// Escapes a CSV field.
+fn csv_field(s: &str) -> Cow<'_, str> {
+	if s.contains([',', '"', '\n', '\r']) {
+		Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+	} else {
+		Cow::Borrowed(s)
+	}
+}
+
+/// Writes a line of CSV fields to `csv`.
+pub fn write_csv_line(csv: &mut String, fields: &[String]) {
+	for (i, field) in fields.iter().enumerate() {
+		if i > 0 {
+			csv.push(',');
+		}
+		csv.push_str(&csv_field(field));
+	}
+	csv.push_str("\r\n");
+}
+
 /// Fetches a file from the given URL and returns its content.
 pub async fn fetch(url: &str, auth: Option<(&str, &str)>) -> Result<Vec<u8>> {
 	trace!(url, "fetch resource");
@@ -100,3 +123,90 @@ impl<T: Renewable> Renewer<T> {
 		self.inner.read().unwrap()
 	}
 }
+
+/// Maximum number of idle connections kept by a [`ConnectionPool`].
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// A pool of dedicated database connections.
+///
+/// Transactions require exclusive access to a connection. Running them on the shared client would
+/// block every other user of the database until they complete, so they are run on connections
+/// taken from this pool instead.
+pub struct ConnectionPool {
+	url: String,
+	idle: Mutex<Vec<Client>>,
+}
+
+impl ConnectionPool {
+	/// Creates a pool connecting to the database at `url`.
+	///
+	/// Connections are opened lazily.
+	pub fn new(url: String) -> Self {
+		Self {
+			url,
+			idle: Mutex::new(vec![]),
+		}
+	}
+
+	/// Returns an idle connection, or opens a new one.
+	pub async fn get(&self) -> PgResult<PooledClient<'_>> {
+		let idle = {
+			let mut idle = self.idle.lock().unwrap();
+			idle.retain(|client| !client.is_closed());
+			idle.pop()
+		};
+		let client = match idle {
+			Some(client) => client,
+			None => {
+				let (client, connection) = tokio_postgres::connect(&self.url, NoTls).await?;
+				tokio::spawn(async move {
+					if let Err(error) = connection.await {
+						warn!(%error, "pooled database connection error");
+					}
+				});
+				client
+			}
+		};
+		Ok(PooledClient {
+			pool: self,
+			client: Some(client),
+		})
+	}
+}
+
+/// A connection borrowed from a [`ConnectionPool`].
+///
+/// The connection is returned to the pool when dropped.
+pub struct PooledClient<'p> {
+	pool: &'p ConnectionPool,
+	client: Option<Client>,
+}
+
+impl Deref for PooledClient<'_> {
+	type Target = Client;
+
+	fn deref(&self) -> &Self::Target {
+		self.client.as_ref().unwrap()
+	}
+}
+
+impl DerefMut for PooledClient<'_> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		self.client.as_mut().unwrap()
+	}
+}
+
+impl Drop for PooledClient<'_> {
+	fn drop(&mut self) {
+		let Some(client) = self.client.take() else {
+			return;
+		};
+		if client.is_closed() {
+			return;
+		}
+		let mut idle = self.pool.idle.lock().unwrap();
+		if idle.len() < MAX_IDLE_CONNECTIONS {
+			idle.push(client);
+		}
+	}
+}